@@ -1,8 +1,9 @@
 use std::collections::HashMap;
-use std::sync::{Arc, mpsc};
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
 use std::thread::JoinHandle;
 use crossbeam::atomic::AtomicCell;
+use log::{error, warn};
 use eframe::egui;
 use egui::Key::N;
 use rand::Rng;
@@ -18,6 +19,7 @@ use midibox::chord::{Chord, ToChord};
 use midibox::composite::PickChannel;
 use midibox::dropout::random_dropout;
 use midibox::drumlogue::Drumlogue;
+use midibox::learn::Params;
 use midibox::midi::{Midi, ToMidi};
 use midibox::rand::{random_velocity, random_velocity_range};
 use midibox::router::MapRouter;
@@ -51,6 +53,18 @@ fn main() {
     let tempo_midibox = Arc::new(AtomicCell::new(tempo));
     let tempo_ui = tempo_midibox.clone();
 
+    // tempo and pattern can also be learned by a MIDI controller on the first input
+    let bindings = "drums_bindings.txt";
+    let mut params = Params::new()
+        .param("tempo", tempo_midibox.clone(), 0, 1000)
+        .param("pattern", drum_pattern_midibox.clone(), 0, 1);
+    if let Err(e) = params.load(bindings) {
+        warn!("No MIDI bindings loaded from {}: {}", bindings, e);
+    }
+    let params = Arc::new(Mutex::new(params));
+    let _params_connection = Params::listen(params.clone(), 0).ok();
+    let params_ui = params.clone();
+
     let handle = thread::spawn(|| {
         let pattern_left = &vec![
             false, false, false, true, false, false,
@@ -101,16 +115,30 @@ fn main() {
     let _ui_result = eframe::run_simple_native("Drums", options, move |ctx, _frame| {
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.heading("Drums");
+            // pick up changes made by a controller
+            tempo = tempo_ui.load();
+            drum_pattern = drum_pattern_ui.load();
+
             ui.add(egui::Slider::new(&mut tempo, 0..=1000).text("tempo"));
             tempo_ui.store(tempo);
 
             ui.add(egui::Slider::new(&mut drum_pattern, 0..=1).text("pattern"));
             drum_pattern_ui.store(drum_pattern);
 
+            for name in ["tempo", "pattern"] {
+                if ui.button(format!("Learn {name}")).clicked() {
+                    params_ui.lock().unwrap().learn(name);
+                }
+            }
+
             ui.label(format!("Tempo: {tempo}, Drum Pattern: {drum_pattern}"));
         });
     });
 
+    if let Err(e) = params.lock().unwrap().save(bindings) {
+        error!("Failed to save MIDI bindings to {}: {}", bindings, e);
+    }
+
     // TODO: example thread not existing correctly on CTRL-C
     let _midibox_result = handle.join();
 }
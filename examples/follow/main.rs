@@ -0,0 +1,32 @@
+use midibox::follow::KeyTranspose;
+use midibox::meter::Bpm;
+use midibox::player::{PlayerConfig, try_run};
+use midibox::scale::Scale;
+use midibox::sequences::Seq;
+use midibox::tone::Tone;
+
+fn main() {
+    env_logger::init();
+
+    let follower = KeyTranspose::new(Tone::C.oct(3));
+    let _connection = follower.listen(0).unwrap();
+
+    let melody = Seq::new(vec![
+        Tone::C.oct(4) * 2,
+        Tone::E.oct(4),
+        Tone::G.oct(4),
+        Tone::B.oct(4) * 2,
+        Tone::G.oct(4) * 2,
+    ]);
+    let bass = Seq::new(vec![Tone::C.oct(2) * 8]);
+
+    try_run(
+        PlayerConfig::for_port(0),
+        &mut Bpm::new(240),
+        &mut vec![
+            follower.harmonize(melody.midibox(), Scale::major(Tone::C)),
+            // the bass keeps playing the root
+            bass.midibox(),
+        ]
+    ).unwrap()
+}
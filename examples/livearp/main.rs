@@ -0,0 +1,28 @@
+use std::sync::{Arc, Mutex};
+use midibox::arp::{Beat, LiveArpeggio, Pattern, Rhythm, UpDown};
+use midibox::input::{HeldKeys, KeyMode};
+use midibox::meter::Bpm;
+use midibox::player::{PlayerConfig, try_run};
+
+fn main() {
+    env_logger::init();
+
+    let keys = Arc::new(Mutex::new(HeldKeys::new(KeyMode::Latch)));
+    let pattern = Pattern::of(UpDown::new(4, false))
+        .octaves(2)
+        .rhythm(Rhythm::new(vec![
+            Beat::Accent(4),
+            Beat::Play(4),
+            Beat::Rest(2),
+            Beat::Play(2),
+            Beat::Play(4),
+        ]).gate(70));
+
+    try_run(
+        PlayerConfig::for_port(0),
+        &mut Bpm::new(480),
+        &mut vec![
+            LiveArpeggio::listen(0, keys, pattern).unwrap(),
+        ]
+    ).unwrap()
+}
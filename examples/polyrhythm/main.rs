@@ -4,6 +4,7 @@ use midibox::tone::Tone;
 use midibox::drumlogue::Drumlogue;
 use midibox::drumlogue::Drumlogue::{BD, CH, LT, OH, SP1};
 use midibox::meter::Bpm;
+use midibox::period::combined_period;
 use midibox::sequences::Seq;
 use midibox::player::{PlayerConfig, try_run};
 use midibox::router::MapRouter;
@@ -34,77 +35,84 @@ fn main() {
 
     assert_eq!(fast.total_duration(), slow_ff1.total_duration());
 
+    let mut channels = vec![
+        (
+            fast.clone()
+                + fast.clone().transpose_down(Interval::Perf4)
+                + fast.clone()
+                + fast.clone().transpose_down(Interval::Min3)
+                + fast.clone()
+                + fast.clone().transpose_down(Interval::Min2)
+                + fast.clone()
+                + fast.clone().transpose_up(Interval::Maj3)
+        ).midibox(),
+        (
+            slow_ff1.clone()
+                .split_notes(&vec![true, false, false])
+                + slow_ff1.clone()
+                .split_notes(&vec![false, true, false, false, true])
+                .transpose_down(Interval::Perf4)
+                + slow_ff1.clone()
+                .split_notes(&vec![true, false, false])
+                + slow_ff1.clone()
+                .split_notes(&vec![false, true, false, false, true])
+                .transpose_down(Interval::Min3)
+                + slow_ff1.clone()
+                .split_notes(&vec![true, false, false])
+                + slow_ff1.clone()
+                .split_notes(&vec![false, true, false, false, true])
+                .transpose_down(Interval::Min2)
+                + slow_ff1.clone()
+                .split_notes(&vec![true, false, false])
+                + slow_ff1.clone()
+                .split_notes(&vec![false, true, false, false, true])
+                .transpose_up(Interval::Maj3)
+        ).midibox(),
+
+        Seq::new(vec![
+            BD * 1,
+            LT * 1,
+            BD * 1,
+            BD * 1,
+            CP * 2,
+            Rest * 2,
+            BD * 2,
+            BD * 1,
+            BD * 1,
+            CP * 1,
+            BD * 3,
+        ]).midibox(),
+        Seq::new(vec![
+            Rest * 2,
+            LT * 4,
+            LT * 2,
+            LT * 2,
+        ]).midibox(),
+        Seq::new(vec![
+            CH * 1,
+            CH * 1,
+            OH * 1,
+            CH * 1,
+            CH * 1,
+            OH * 1,
+            CH * 1,
+            OH * 1,
+        ]).midibox(),
+        Seq::new(vec![
+            SP1 * 5
+        ])
+            .split_notes(&vec![true, false, false, false, true])
+            .midibox()
+    ];
+
+    match combined_period(&channels) {
+        Some(ticks) => println!("Pattern repeats every {} ticks", ticks),
+        None => println!("Pattern never repeats"),
+    }
+
     try_run(
         PlayerConfig::from_router(Box::new(MapRouter::new(channel_id_to_port_id))),
         &mut Bpm::new( 500),
-        & mut vec![
-            (
-                fast.clone()
-                    + fast.clone().transpose_down(Interval::Perf4)
-                    + fast.clone()
-                    + fast.clone().transpose_down(Interval::Min3)
-                    + fast.clone()
-                    + fast.clone().transpose_down(Interval::Min2)
-                    + fast.clone()
-                    + fast.clone().transpose_up(Interval::Maj3)
-            ).midibox(),
-            (
-                slow_ff1.clone()
-                    .split_notes(&vec![true, false, false])
-                    + slow_ff1.clone()
-                    .split_notes(&vec![false, true, false, false, true])
-                    .transpose_down(Interval::Perf4)
-                    + slow_ff1.clone()
-                    .split_notes(&vec![true, false, false])
-                    + slow_ff1.clone()
-                    .split_notes(&vec![false, true, false, false, true])
-                    .transpose_down(Interval::Min3)
-                    + slow_ff1.clone()
-                    .split_notes(&vec![true, false, false])
-                    + slow_ff1.clone()
-                    .split_notes(&vec![false, true, false, false, true])
-                    .transpose_down(Interval::Min2)
-                    + slow_ff1.clone()
-                    .split_notes(&vec![true, false, false])
-                    + slow_ff1.clone()
-                    .split_notes(&vec![false, true, false, false, true])
-                    .transpose_up(Interval::Maj3)
-            ).midibox(),
-
-            Seq::new(vec![
-                BD * 1,
-                LT * 1,
-                BD * 1,
-                BD * 1,
-                CP * 2,
-                Rest * 2,
-                BD * 2,
-                BD * 1,
-                BD * 1,
-                CP * 1,
-                BD * 3,
-            ]).midibox(),
-            Seq::new(vec![
-                Rest * 2,
-                LT * 4,
-                LT * 2,
-                LT * 2,
-            ]).midibox(),
-            Seq::new(vec![
-                CH * 1,
-                CH * 1,
-                OH * 1,
-                CH * 1,
-                CH * 1,
-                OH * 1,
-                CH * 1,
-                OH * 1,
-            ]).midibox(),
-            Seq::new(vec![
-                SP1 * 5
-            ])
-                .split_notes(&vec![true, false, false, false, true])
-                .midibox()
-        ]
+        &mut channels
     ).unwrap()
 }
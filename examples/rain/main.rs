@@ -7,7 +7,7 @@ use midibox::sequences::Seq;
 use midibox::player::{PlayerConfig, try_run};
 use midibox::scale::{Degree, Direction, Interval, Scale};
 use midibox::{map_chords, map_notes, Midibox, seq};
-use midibox::arp::Arpeggio;
+use midibox::arp::{Arpeggio, Ascend, Beat, Pattern, Rhythm};
 use midibox::chord::{Chord, ToChord};
 use midibox::dropout::random_dropout;
 use midibox::drumlogue::Drumlogue;
@@ -33,7 +33,15 @@ fn main() {
         &mut vec![
             bass(scale()),
             Arpeggio::ascend(base_chords(scale()), 4),
-            Arpeggio::ascend(harm(scale(), 3), 3),
+            Arpeggio::wrap(harm(scale(), 3), Pattern::of(Ascend::new(3)).rhythm(
+                Rhythm::new(vec![
+                    Beat::Play(3),
+                    Beat::Play(3),
+                    Beat::Rest(2),
+                    Beat::Accent(3),
+                    Beat::Tie(1),
+                ]).gate(60)
+            )),
             Arpeggio::descend(harm(scale(), 4), 2),
         ]
     ).unwrap()
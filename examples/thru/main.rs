@@ -0,0 +1,22 @@
+use std::thread::sleep;
+use std::time::Duration;
+use midibox::player::PlayerConfig;
+use midibox::scale::{Degree, Scale, Snap};
+use midibox::thru::Thru;
+use midibox::tone::Tone;
+
+fn main() {
+    env_logger::init();
+
+    let scale = Scale::major(Tone::D);
+    let _connection = Thru::new()
+        .quantize(scale.clone(), Snap::Nearest)
+        .harmonize_up(scale, Degree::Third)
+        .velocity_curve(|v| 64 + v / 2)
+        .listen(0, PlayerConfig::for_port(0))
+        .unwrap();
+
+    loop {
+        sleep(Duration::from_secs(1));
+    }
+}
@@ -1,6 +1,15 @@
+use std::cell::RefCell;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use midir::MidiInputConnection;
+use rand::Rng;
+use rand::rngs::StdRng;
 use crate::Midibox;
 use crate::chord::Chord;
+use crate::input::{HeldKeys, listen};
 use crate::midi::{Midi, MutMidi};
+use crate::period::Period;
+use crate::rand::session_rng;
 use crate::sequences::Seq;
 
 pub struct Arpeggio {
@@ -15,21 +24,175 @@ pub struct Arpeggio {
     // what chord are we playing?
     current_chord: Option<Chord>,
     // how should it be played?
-    pattern: Pattern
+    pattern: Pattern,
+    // which step of the pattern's rhythm is next?
+    rhythm_position: usize,
 }
 
 pub struct Pattern {
-    mask: Vec<Box<dyn SelectMidi>>
+    mask: Vec<Box<dyn SelectMidi>>,
+    // how many octaves the chord is spread across
+    octaves: u8,
+    // when the selected notes are played, if not back-to-back
+    rhythm: Option<Rhythm>,
 }
 
-trait SelectMidi {
+impl Pattern {
+    /// Plays the notes chosen by each of the selections together
+    pub fn new(mask: Vec<Box<dyn SelectMidi>>) -> Self {
+        Pattern { mask, octaves: 1, rhythm: None }
+    }
+
+    pub fn of<S>(select: S) -> Self where S: SelectMidi + 'static {
+        Pattern::new(vec![Box::new(select)])
+    }
+
+    /// Extends each chord with copies of its notes in the `octaves - 1` octaves above it
+    pub fn octaves(mut self, octaves: u8) -> Self {
+        self.octaves = octaves.max(1);
+        self
+    }
+
+    /// Plays the selected notes to the given rhythm instead of back-to-back
+    pub fn rhythm(mut self, rhythm: Rhythm) -> Self {
+        self.rhythm = Some(rhythm);
+        self
+    }
+
+    /// Plays one step of the pattern over the chord. Returns the notes, and whether the step
+    /// played the selected notes rather than resting.
+    fn play(
+        &self,
+        chord: &Chord,
+        iterations_at_position: usize,
+        rhythm_position: &mut usize
+    ) -> (Vec<Midi>, bool) {
+        let selected: Vec<Midi> = self.mask.iter()
+            .flat_map(|select| select.select(chord, iterations_at_position))
+            .collect();
+        match &self.rhythm {
+            None => (selected, true),
+            Some(rhythm) => rhythm.play(selected, rhythm_position),
+        }
+    }
+
+    fn extend(&self, chord: &Chord) -> Chord {
+        let mut notes = chord.notes.clone();
+        for octave in 1..self.octaves {
+            notes.extend(chord.notes.iter()
+                .filter_map(|n| n.u8_maybe()
+                    .map(|v| v as u16 + 12 * octave as u16)
+                    .filter(|v| *v <= 127)
+                    .map(|v| n.set_pitch_u8(Some(v as u8)))));
+        }
+        Chord::new(notes)
+    }
+
+    fn is_periodic(&self) -> bool {
+        self.mask.iter().all(|select| select.is_periodic())
+    }
+}
+
+/// A step of an arpeggio's rhythm, lasting the given number of ticks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Beat {
+    /// Plays the next notes of the arpeggio
+    Play(u32),
+    /// Plays the next notes of the arpeggio with an accent
+    Accent(u32),
+    /// Rests without moving the arpeggio along
+    Rest(u32),
+    /// Holds the notes of the preceding step for longer. A tie after a rest rests.
+    Tie(u32),
+}
+
+/// A looping rhythm for an arpeggio.
+///
+/// The gate is the percentage of each step that its notes sound for, so that shortening the
+/// notes doesn't change when the following step starts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rhythm {
+    beats: Vec<Beat>,
+    gate: u8,
+    accent: f64,
+}
+
+impl Rhythm {
+    pub fn new(beats: Vec<Beat>) -> Self {
+        Rhythm { beats, gate: 100, accent: 1.25 }
+    }
+
+    /// The percentage of each step that its notes sound for, from 1 to 100
+    pub fn gate(mut self, percent: u8) -> Self {
+        self.gate = percent.clamp(1, 100);
+        self
+    }
+
+    /// The factor applied to the velocity of accented notes
+    pub fn accent(mut self, factor: f64) -> Self {
+        self.accent = factor;
+        self
+    }
+
+    fn play(&self, notes: Vec<Midi>, position: &mut usize) -> (Vec<Midi>, bool) {
+        if self.beats.is_empty() {
+            return (notes, true);
+        }
+        let beat = self.beats[*position % self.beats.len()];
+        *position = (*position + 1) % self.beats.len();
+        let (length, accent) = match beat {
+            Beat::Rest(length) | Beat::Tie(length) => {
+                return (vec![Midi::rest().set_duration(length)], false)
+            }
+            Beat::Play(length) => (length, 1.0),
+            Beat::Accent(length) => (length, self.accent),
+        };
+
+        // fold the ties that follow into this step
+        let mut length = length;
+        for _ in 1..self.beats.len() {
+            match self.beats[*position] {
+                Beat::Tie(tied) => length += tied,
+                _ => break,
+            }
+            *position = (*position + 1) % self.beats.len();
+        }
+
+        let sounding = (length as f64 * self.gate as f64 / 100.0).round() as u32;
+        let sounding = sounding.clamp(1, length.max(1));
+        let mut played: Vec<Midi> = notes.into_iter()
+            .map(|n| n.set_duration(sounding)
+                .set_velocity((n.velocity as f64 * accent).round().clamp(0.0, 127.0) as u8))
+            .collect();
+        if sounding < length {
+            // hold the step for its full length
+            played.push(Midi::rest().set_duration(length));
+        }
+        (played, true)
+    }
+}
+
+/// Chooses the notes of a chord to play on each step of an arpeggio
+pub trait SelectMidi: Send {
     fn select(&self, chord: &Chord, iterations_at_position: usize) -> Vec<Midi>;
+
+    /// Whether the same chord and iteration always give the same notes
+    fn is_periodic(&self) -> bool {
+        true
+    }
 }
 
+/// Plays the notes in the order they're listed in the chord
 pub struct Ascend {
     note_duration: u32,
 }
 
+impl Ascend {
+    pub fn new(note_duration: u32) -> Self {
+        Ascend { note_duration }
+    }
+}
+
 impl SelectMidi for Ascend {
     fn select(&self, chord: &Chord, ticks: usize) -> Vec<Midi> {
         let selected = chord.notes.get(ticks % chord.notes.len());
@@ -40,10 +203,17 @@ impl SelectMidi for Ascend {
     }
 }
 
+/// Plays the notes in the reverse of the order they're listed in the chord
 pub struct Descend {
     note_duration: u32,
 }
 
+impl Descend {
+    pub fn new(note_duration: u32) -> Self {
+        Descend { note_duration }
+    }
+}
+
 impl SelectMidi for Descend {
     fn select(&self, chord: &Chord, ticks: usize) -> Vec<Midi> {
         let selected = chord.notes.get((chord.notes.len() - 1) - ticks % chord.notes.len());
@@ -59,6 +229,12 @@ pub struct CustomOrder {
     note_duration: u32
 }
 
+impl CustomOrder {
+    pub fn new(note_duration: u32, note_order: Vec<usize>) -> Self {
+        CustomOrder { note_order, note_duration }
+    }
+}
+
 impl SelectMidi for CustomOrder {
     fn select(&self, chord: &Chord, iterations_at_position: usize) -> Vec<Midi> {
         let to_play: Option<&Midi> = self.note_order.get(
@@ -71,6 +247,136 @@ impl SelectMidi for CustomOrder {
     }
 }
 
+/// The notes of the chord from lowest to highest
+fn by_pitch(chord: &Chord) -> Vec<Midi> {
+    let mut notes = chord.notes.clone();
+    notes.sort_by_key(|n| n.u8_maybe());
+    notes
+}
+
+/// Plays the note at `order[iteration]` of `notes`, looping through the order
+fn select_in_order(notes: &[Midi], order: &[usize], iteration: usize, duration: u32) -> Vec<Midi> {
+    if order.is_empty() {
+        return vec![];
+    }
+    notes.get(order[iteration % order.len()])
+        .map(|n| vec![n.set_duration(duration)])
+        .unwrap_or_default()
+}
+
+/// Plays the notes in the order they were added to the chord, whatever their pitch. For the
+/// chords of [HeldKeys], that's the order the keys were pressed in.
+pub struct AsPlayed {
+    note_duration: u32,
+}
+
+impl AsPlayed {
+    pub fn new(note_duration: u32) -> Self {
+        AsPlayed { note_duration }
+    }
+}
+
+impl SelectMidi for AsPlayed {
+    fn select(&self, chord: &Chord, iterations_at_position: usize) -> Vec<Midi> {
+        let order: Vec<usize> = (0..chord.notes.len()).collect();
+        select_in_order(&chord.notes, &order, iterations_at_position, self.note_duration)
+    }
+}
+
+/// Walks up from the lowest note to the highest, then back down. With `repeat_ends`, the
+/// highest and lowest notes are played twice in a row.
+pub struct UpDown {
+    note_duration: u32,
+    repeat_ends: bool,
+}
+
+impl UpDown {
+    pub fn new(note_duration: u32, repeat_ends: bool) -> Self {
+        UpDown { note_duration, repeat_ends }
+    }
+}
+
+impl SelectMidi for UpDown {
+    fn select(&self, chord: &Chord, iterations_at_position: usize) -> Vec<Midi> {
+        let notes = by_pitch(chord);
+        let len = notes.len();
+        let down: Vec<usize> = if self.repeat_ends {
+            (0..len).rev().collect()
+        } else {
+            (1..len.saturating_sub(1)).rev().collect()
+        };
+        let order: Vec<usize> = (0..len).chain(down).collect();
+        select_in_order(&notes, &order, iterations_at_position, self.note_duration)
+    }
+}
+
+/// Alternates between the outermost notes, moving towards the middle of the chord
+pub struct Converge {
+    note_duration: u32,
+}
+
+impl Converge {
+    pub fn new(note_duration: u32) -> Self {
+        Converge { note_duration }
+    }
+}
+
+fn converging_order(len: usize) -> Vec<usize> {
+    (0..len).map(|i| if i % 2 == 0 { i / 2 } else { len - 1 - i / 2 }).collect()
+}
+
+impl SelectMidi for Converge {
+    fn select(&self, chord: &Chord, iterations_at_position: usize) -> Vec<Midi> {
+        let notes = by_pitch(chord);
+        let order = converging_order(notes.len());
+        select_in_order(&notes, &order, iterations_at_position, self.note_duration)
+    }
+}
+
+/// Starts in the middle of the chord and alternates outwards, the reverse of [Converge]
+pub struct Diverge {
+    note_duration: u32,
+}
+
+impl Diverge {
+    pub fn new(note_duration: u32) -> Self {
+        Diverge { note_duration }
+    }
+}
+
+impl SelectMidi for Diverge {
+    fn select(&self, chord: &Chord, iterations_at_position: usize) -> Vec<Midi> {
+        let notes = by_pitch(chord);
+        let order: Vec<usize> = converging_order(notes.len()).into_iter().rev().collect();
+        select_in_order(&notes, &order, iterations_at_position, self.note_duration)
+    }
+}
+
+/// Plays a randomly chosen note of the chord on every step
+pub struct Random {
+    note_duration: u32,
+    rng: RefCell<StdRng>,
+}
+
+impl Random {
+    pub fn new(note_duration: u32, rng: StdRng) -> Self {
+        Random { note_duration, rng: RefCell::new(rng) }
+    }
+}
+
+impl SelectMidi for Random {
+    fn select(&self, chord: &Chord, _: usize) -> Vec<Midi> {
+        if chord.notes.is_empty() {
+            return vec![];
+        }
+        let position = self.rng.borrow_mut().gen_range(0..chord.notes.len());
+        vec![chord.notes[position].set_duration(self.note_duration)]
+    }
+
+    fn is_periodic(&self) -> bool {
+        false
+    }
+}
 
 impl Arpeggio {
     pub fn wrap(seq: Seq, pattern: Pattern) -> Box<dyn Midibox> {
@@ -80,26 +386,42 @@ impl Arpeggio {
             duration_at_position: 0,
             to_play: seq,
             current_chord: None,
-            pattern
+            pattern,
+            rhythm_position: 0,
         })
     }
 
     pub fn ascend(seq: Seq, note_duration: u32) -> Box<dyn Midibox> {
-        Arpeggio::wrap(seq, Pattern {
-            mask: vec![Box::new(Ascend { note_duration })]
-        })
+        Arpeggio::wrap(seq, Pattern::of(Ascend::new(note_duration)))
     }
 
     pub fn descend(seq: Seq, note_duration: u32) -> Box<dyn Midibox> {
-        Arpeggio::wrap(seq, Pattern {
-            mask: vec![Box::new(Descend { note_duration })]
-        })
+        Arpeggio::wrap(seq, Pattern::of(Descend::new(note_duration)))
     }
 
     pub fn custom_order(seq: Seq, note_duration: u32, note_order: Vec<usize>) -> Box<dyn Midibox> {
-        Arpeggio::wrap(seq, Pattern {
-            mask: vec![Box::new(CustomOrder { note_order, note_duration })]
-        })
+        Arpeggio::wrap(seq, Pattern::of(CustomOrder::new(note_duration, note_order)))
+    }
+
+    pub fn as_played(seq: Seq, note_duration: u32) -> Box<dyn Midibox> {
+        Arpeggio::wrap(seq, Pattern::of(AsPlayed::new(note_duration)))
+    }
+
+    pub fn up_down(seq: Seq, note_duration: u32, repeat_ends: bool) -> Box<dyn Midibox> {
+        Arpeggio::wrap(seq, Pattern::of(UpDown::new(note_duration, repeat_ends)))
+    }
+
+    pub fn converge(seq: Seq, note_duration: u32) -> Box<dyn Midibox> {
+        Arpeggio::wrap(seq, Pattern::of(Converge::new(note_duration)))
+    }
+
+    pub fn diverge(seq: Seq, note_duration: u32) -> Box<dyn Midibox> {
+        Arpeggio::wrap(seq, Pattern::of(Diverge::new(note_duration)))
+    }
+
+    /// Plays random notes of each chord, drawn from the session's random numbers
+    pub fn random(seq: Seq, note_duration: u32) -> Box<dyn Midibox> {
+        Arpeggio::wrap(seq, Pattern::of(Random::new(note_duration, session_rng())))
     }
 }
 
@@ -118,18 +440,21 @@ impl Midibox for Arpeggio {
             self.duration_at_position = 0;
             return None;
         }
-        let chord = self.current_chord.clone().unwrap();
+        let chord = self.pattern.extend(self.current_chord.as_ref().unwrap());
 
-        let mut result: Vec<Midi> = vec![];
-        for select in self.pattern.mask.iter() {
-            result.append(&mut select.select(&chord, self.iterations_at_position));
-        }
+        let (result, advance) = self.pattern.play(
+            &chord,
+            self.iterations_at_position,
+            &mut self.rhythm_position
+        );
         let max_duration = result.iter()
             .map(|to_play| to_play.duration)
             .max()
             .unwrap_or(0);
 
-        self.iterations_at_position += 1;
+        if advance {
+            self.iterations_at_position += 1;
+        }
         self.duration_at_position += max_duration;
 
         if self.duration_at_position >= chord.total_duration() {
@@ -141,4 +466,216 @@ impl Midibox for Arpeggio {
 
         return Some(result.clone());
     }
+
+    fn reset(&mut self) {
+        self.chord_position = 0;
+        self.iterations_at_position = 0;
+        self.duration_at_position = 0;
+        self.current_chord = None;
+        self.rhythm_position = 0;
+    }
+
+    /// Walks through passes of the chords until the rhythm lines up with the start of a pass,
+    /// which always brings the arpeggio back to its start
+    fn period(&self) -> Option<Period> {
+        if !self.pattern.is_periodic() {
+            return None;
+        }
+        let passes = self.pattern.rhythm.as_ref().map_or(1, |r| r.beats.len().max(1));
+        let mut durations: Vec<u64> = vec![];
+        let mut rhythm_position = 0;
+        for _ in 0..passes {
+            for chord in self.to_play.get_chords() {
+                let total = chord.total_duration();
+                let chord = &self.pattern.extend(chord);
+                let mut duration_at_position = 0;
+                let mut iterations_at_position = 0;
+                loop {
+                    let (notes, advance) = self.pattern.play(
+                        chord,
+                        iterations_at_position,
+                        &mut rhythm_position
+                    );
+                    let step = notes.iter().map(|to_play| to_play.duration).max().unwrap_or(0);
+                    durations.push(step as u64);
+                    duration_at_position += step;
+                    if advance {
+                        iterations_at_position += 1;
+                    }
+                    if duration_at_position >= total {
+                        break;
+                    }
+                    if step == 0 {
+                        // the arpeggio never moves past this chord
+                        return None;
+                    }
+                }
+            }
+            if rhythm_position == 0 {
+                return Period::of_steps(&durations);
+            }
+        }
+        None
+    }
+}
+
+/// Arpeggiates the keys played on a MIDI input, see [HeldKeys].
+///
+/// The arpeggio keeps its place when keys are added or released, and starts over once there
+/// are no keys left to play.
+pub struct LiveArpeggio {
+    keys: Arc<Mutex<HeldKeys>>,
+    pattern: Pattern,
+    iterations: usize,
+    rhythm_position: usize,
+    // keeps the input open while the arpeggio plays
+    _connection: Option<MidiInputConnection<()>>,
+}
+
+impl LiveArpeggio {
+    /// Arpeggiates keys fed to `keys` from elsewhere
+    pub fn wrap(keys: Arc<Mutex<HeldKeys>>, pattern: Pattern) -> Box<dyn Midibox> {
+        Box::new(LiveArpeggio {
+            keys,
+            pattern,
+            iterations: 0,
+            rhythm_position: 0,
+            _connection: None,
+        })
+    }
+
+    /// Arpeggiates the keys played on the MIDI input port with the given index. `keys` can be
+    /// used to change the mode or clear the chord while playing.
+    pub fn listen(
+        port_id: usize,
+        keys: Arc<Mutex<HeldKeys>>,
+        pattern: Pattern
+    ) -> Result<Box<dyn Midibox>, Box<dyn Error>> {
+        let input_keys = Arc::clone(&keys);
+        let connection = listen(port_id, move |message| {
+            input_keys.lock().unwrap().handle(message)
+        })?;
+        Ok(Box::new(LiveArpeggio {
+            keys,
+            pattern,
+            iterations: 0,
+            rhythm_position: 0,
+            _connection: Some(connection),
+        }))
+    }
+}
+
+impl Midibox for LiveArpeggio {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        let chord = self.pattern.extend(&self.keys.lock().unwrap().chord());
+        if chord.notes.is_empty() {
+            self.reset();
+            return Some(vec![Midi::rest()]);
+        }
+        let (notes, advance) = self.pattern.play(
+            &chord,
+            self.iterations,
+            &mut self.rhythm_position
+        );
+        if advance {
+            self.iterations += 1;
+        }
+        Some(notes)
+    }
+
+    fn reset(&mut self) {
+        self.iterations = 0;
+        self.rhythm_position = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use crate::arp::{
+        Arpeggio, AsPlayed, Ascend, Beat, Converge, Diverge, LiveArpeggio, Pattern, Random, Rhythm,
+        UpDown
+    };
+    use crate::input::{HeldKeys, KeyMode};
+    use crate::period::Period;
+    use crate::chord::Chord;
+    use crate::sequences::Seq;
+    use crate::tone::Tone;
+
+    fn tones(seq: Seq, pattern: Pattern, steps: usize) -> Vec<(Tone, u8)> {
+        let mut arp = Arpeggio::wrap(seq, pattern);
+        (0..steps).map(|_| arp.next().unwrap()[0]).map(|n| (n.tone, n.oct)).collect()
+    }
+
+    #[test]
+    fn modes() {
+        let chord = Chord::new(vec![Tone::G.oct(4), Tone::C.oct(4), Tone::E.oct(4)]);
+        let seq = Seq::chords(vec![chord]).duration(100);
+        assert_eq!(tones(seq.clone(), Pattern::of(UpDown::new(1, false)), 5), vec![
+            (Tone::C, 4), (Tone::E, 4), (Tone::G, 4), (Tone::E, 4), (Tone::C, 4),
+        ]);
+        assert_eq!(tones(seq.clone(), Pattern::of(UpDown::new(1, true)), 4), vec![
+            (Tone::C, 4), (Tone::E, 4), (Tone::G, 4), (Tone::G, 4),
+        ]);
+        assert_eq!(tones(seq, Pattern::of(UpDown::new(1, false)).octaves(2), 7), vec![
+            (Tone::C, 4), (Tone::E, 4), (Tone::G, 4),
+            (Tone::C, 5), (Tone::E, 5), (Tone::G, 5), (Tone::E, 5),
+        ]);
+
+        let chord = Chord::new(vec![Tone::E.oct(4), Tone::B.oct(4), Tone::C.oct(4), Tone::G.oct(4)]);
+        let seq = Seq::chords(vec![chord]).duration(100);
+        assert_eq!(tones(seq.clone(), Pattern::of(Converge::new(1)), 5), vec![
+            (Tone::C, 4), (Tone::B, 4), (Tone::E, 4), (Tone::G, 4), (Tone::C, 4),
+        ]);
+        assert_eq!(tones(seq.clone(), Pattern::of(Diverge::new(1)), 5), vec![
+            (Tone::G, 4), (Tone::E, 4), (Tone::B, 4), (Tone::C, 4), (Tone::G, 4),
+        ]);
+        let random = Pattern::of(Random::new(1, StdRng::seed_from_u64(7)));
+        let mut played = tones(seq, random, 40);
+        played.sort_by_key(|(tone, _)| *tone as u8);
+        played.dedup();
+        assert_eq!(played, vec![(Tone::C, 4), (Tone::E, 4), (Tone::G, 4), (Tone::B, 4)]);
+    }
+
+    #[test]
+    fn rhythm() {
+        let chord = Chord::new(vec![Tone::C.oct(4), Tone::E.oct(4)]);
+        let seq = Seq::chords(vec![chord]).duration(12);
+        let rhythm = Rhythm::new(vec![Beat::Accent(2), Beat::Tie(1), Beat::Rest(1), Beat::Play(2)])
+            .gate(50);
+        let mut arp = Arpeggio::wrap(seq, Pattern::of(Ascend::new(1)).rhythm(rhythm));
+        let steps: Vec<Vec<(Tone, u32, u8)>> = (0..4)
+            .map(|_| arp.next().unwrap().iter().map(|n| (n.tone, n.duration, n.velocity)).collect())
+            .collect();
+        assert_eq!(steps, vec![
+            vec![(Tone::C, 2, 125), (Tone::Rest, 3, 100)],
+            vec![(Tone::Rest, 1, 100)],
+            vec![(Tone::E, 1, 100), (Tone::Rest, 2, 100)],
+            vec![(Tone::C, 2, 125), (Tone::Rest, 3, 100)],
+        ]);
+        assert_eq!(arp.period(), Some(Period::new(12, 6, 12)));
+    }
+
+    #[test]
+    fn live() {
+        let keys = Arc::new(Mutex::new(HeldKeys::new(KeyMode::Held)));
+        let mut arp = LiveArpeggio::wrap(keys.clone(), Pattern::of(UpDown::new(2, false)));
+        assert!(arp.next().unwrap()[0].is_rest());
+        keys.lock().unwrap().press(67, 100);
+        keys.lock().unwrap().press(60, 100);
+        assert_eq!(arp.next().unwrap()[0].tone, Tone::C);
+        assert_eq!(arp.next().unwrap()[0].tone, Tone::G);
+        keys.lock().unwrap().release(67);
+        assert_eq!(arp.next().unwrap()[0].tone, Tone::C);
+
+        let keys = Arc::new(Mutex::new(HeldKeys::new(KeyMode::Held)));
+        let mut arp = LiveArpeggio::wrap(keys.clone(), Pattern::of(AsPlayed::new(1)));
+        for key in [67, 60, 64] {
+            keys.lock().unwrap().press(key, 100);
+        }
+        let played: Vec<Tone> = (0..4).map(|_| arp.next().unwrap()[0].tone).collect();
+        assert_eq!(played, vec![Tone::G, Tone::C, Tone::E, Tone::G]);
+    }
 }
@@ -0,0 +1,285 @@
+use crate::Midibox;
+use crate::chord::Chord;
+use crate::composite::chain;
+use crate::midi::Midi;
+use crate::scale::Scale;
+use crate::sequences::{Condition, IterSeq, Seq};
+
+
+/// An elementary cellular automaton: a row of cells that evolves according to a Wolfram rule
+/// number. The row wraps around at the edges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Automaton {
+    rule: u8,
+    cells: Vec<bool>,
+}
+
+impl Automaton {
+    /// Starts with a single live cell in the middle of the row
+    pub fn new(rule: u8, width: usize) -> Self {
+        let mut cells = vec![false; width];
+        if width > 0 {
+            cells[width / 2] = true;
+        }
+        Automaton::from_cells(rule, cells)
+    }
+
+    pub fn from_cells(rule: u8, cells: Vec<bool>) -> Self {
+        Automaton { rule, cells }
+    }
+
+    pub fn cells(&self) -> &Vec<bool> {
+        &self.cells
+    }
+
+    /// Moves on to the next row
+    pub fn step(&mut self) {
+        let width = self.cells.len();
+        self.cells = (0..width)
+            .map(|i| {
+                let left = self.cells[(i + width - 1) % width] as u8;
+                let center = self.cells[i] as u8;
+                let right = self.cells[(i + 1) % width] as u8;
+                (self.rule >> (left << 2 | center << 1 | right)) & 1 == 1
+            })
+            .collect();
+    }
+
+    /// The next `count` rows, starting with the current one
+    pub fn rows(&self, count: usize) -> Vec<Vec<bool>> {
+        let mut automaton = self.clone();
+        (0..count)
+            .map(|_| {
+                let row = automaton.cells.clone();
+                automaton.step();
+                row
+            })
+            .collect()
+    }
+
+    /// Plays the sequence in a loop, masked by a new row of the automaton every time around,
+    /// see [Seq::mask]
+    pub fn rhythm(self, seq: Seq) -> Box<dyn Midibox> {
+        Box::new(Evolve {
+            current: seq.clone().mask(&self.cells).render(),
+            start: self.clone(),
+            automaton: self,
+            seq,
+            position: 0,
+        })
+    }
+
+    /// Plays each row as `width` steps of `note_duration` ticks, where the live cells play
+    /// tones of the scale rising across the range of octaves and the dead ones rest
+    pub fn melody(
+        self,
+        scale: &Scale,
+        low_oct: u8,
+        high_oct: u8,
+        note_duration: u32
+    ) -> Box<dyn Midibox> {
+        let notes = scale.range(low_oct, high_oct);
+        Box::new(Melody {
+            notes,
+            start: self.clone(),
+            automaton: self,
+            position: 0,
+            note_duration,
+        })
+    }
+}
+
+pub struct Evolve {
+    automaton: Automaton,
+    start: Automaton,
+    seq: Seq,
+    // the sequence masked by the current row
+    current: IterSeq,
+    // the index of the next step into the sequence
+    position: usize,
+}
+
+impl Midibox for Evolve {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        if self.position >= self.seq.len() {
+            self.automaton.step();
+            self.current = self.seq.clone().mask(&self.automaton.cells).render();
+            self.position = 0;
+        }
+        self.position += 1;
+        self.current.next()
+    }
+
+    fn reset(&mut self) {
+        self.automaton = self.start.clone();
+        self.current = self.seq.clone().mask(&self.automaton.cells).render();
+        self.position = 0;
+    }
+}
+
+pub struct Melody {
+    automaton: Automaton,
+    start: Automaton,
+    // the tones of the scale within range, from lowest to highest
+    notes: Vec<Midi>,
+    // the index of the next cell into the row
+    position: usize,
+    note_duration: u32,
+}
+
+impl Midibox for Melody {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        let width = self.automaton.cells.len();
+        if width == 0 || self.notes.is_empty() {
+            return None;
+        }
+        if self.position >= width {
+            self.automaton.step();
+            self.position = 0;
+        }
+        let cell = self.position;
+        self.position += 1;
+        let note = if self.automaton.cells[cell] {
+            self.notes[cell * self.notes.len() / width]
+        } else {
+            Midi::rest()
+        };
+        Some(vec![note.set_duration(self.note_duration)])
+    }
+
+    fn reset(&mut self) {
+        self.automaton = self.start.clone();
+        self.position = 0;
+    }
+}
+
+/// Rewrites a sequence by replacing each step with a sequence of steps, over and over.
+///
+/// A rule applies to the steps whose pitches match its predecessor, whatever their durations.
+/// Steps without a matching rule are kept as they are.
+///
+/// A rewritten step's trig condition decides for all of the steps replacing it, as with
+/// ratchets, unless it always plays, in which case the replacement keeps its own conditions.
+/// Every generation shares the fill switch of the axiom, but the generations after it start from
+/// their first step whatever the axiom's head position.
+#[derive(Debug, Clone)]
+pub struct LSystem {
+    axiom: Seq,
+    rules: Vec<(Chord, Seq)>,
+}
+
+impl LSystem {
+    pub fn new(axiom: Seq) -> Self {
+        LSystem { axiom, rules: vec![] }
+    }
+
+    pub fn rule(mut self, predecessor: Chord, successor: Seq) -> Self {
+        self.rules.push((predecessor, successor));
+        self
+    }
+
+    /// The sequence after `n` rewrites of the axiom
+    pub fn generation(&self, n: usize) -> Seq {
+        (0..n).fold(self.axiom.clone(), |seq, _| self.rewrite(&seq))
+    }
+
+    /// Plays the first `generations` generations one after the other, then starts over
+    pub fn evolve(&self, generations: usize) -> Box<dyn Midibox> {
+        chain((0..generations.max(1))
+            .map(|n| self.generation(n))
+            .map(|seq| {
+                let ticks = seq.total_duration();
+                (seq.midibox(), ticks)
+            })
+            .collect())
+    }
+
+    fn rewrite(&self, seq: &Seq) -> Seq {
+        let pitches = |c: &Chord| c.notes.iter().map(|n| n.u8_maybe()).collect::<Vec<_>>();
+        let mut chords: Vec<Chord> = vec![];
+        let mut conditions: Vec<Condition> = vec![];
+        for (chord, condition) in seq.get_chords().iter().zip(seq.get_conditions().iter()) {
+            match self.rules.iter().find(|(from, _)| pitches(from) == pitches(chord)) {
+                Some((_, to)) => {
+                    chords.extend(to.get_chords().iter().cloned());
+                    conditions.extend(to.get_conditions().iter().enumerate().map(|(i, c)| {
+                        match (condition, i) {
+                            (Condition::Always, _) => *c,
+                            (_, 0) => *condition,
+                            _ => Condition::Follow,
+                        }
+                    }));
+                }
+                None => {
+                    chords.push(chord.clone());
+                    conditions.push(*condition);
+                }
+            }
+        }
+        Seq::chords(chords).conditions(&conditions).fill(seq.get_fill())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crossbeam::atomic::AtomicCell;
+    use crate::automata::{Automaton, LSystem};
+    use crate::chord::Chord;
+    use crate::sequences::{Condition, Seq};
+    use crate::tone::Tone;
+
+    #[test]
+    fn rule_90() {
+        let rows = Automaton::new(90, 7).rows(3);
+        assert_eq!(rows, vec![
+            vec![false, false, false, true, false, false, false],
+            vec![false, false, true, false, true, false, false],
+            vec![false, true, false, false, false, true, false],
+        ]);
+    }
+
+    #[test]
+    fn rhythm_evolves() {
+        let seq = Seq::new(vec![Tone::C.oct(4); 3]);
+        let mut rhythm = Automaton::from_cells(90, vec![false, true, false]).rhythm(seq);
+        let played: Vec<bool> = (0..6).map(|_| !rhythm.next().unwrap()[0].is_rest()).collect();
+        assert_eq!(played, vec![false, true, false, true, false, true]);
+    }
+
+    #[test]
+    fn algae() {
+        let a = Chord::note(Tone::A.oct(4));
+        let b = Chord::note(Tone::B.oct(4));
+        let system = LSystem::new(Seq::chords(vec![a.clone()]))
+            .rule(a.clone(), Seq::chords(vec![a.clone(), b.clone()]))
+            .rule(b.clone(), Seq::chords(vec![a.clone()]));
+        let tones: Vec<Tone> = system.generation(4).get_chords().iter()
+            .map(|c| c.notes[0].tone)
+            .collect();
+        assert_eq!(tones, vec![
+            Tone::A, Tone::B, Tone::A, Tone::A, Tone::B, Tone::A, Tone::B, Tone::A,
+        ]);
+    }
+
+    #[test]
+    fn rewrite_keeps_conditions() {
+        let a = Chord::note(Tone::A.oct(4));
+        let b = Chord::note(Tone::B.oct(4));
+        let fill = Arc::new(AtomicCell::new(false));
+        let axiom = Seq::chords(vec![a.clone(), b.clone()])
+            .condition(0, Condition::Every(1, 2))
+            .fill(fill.clone())
+            .fast_forward(1);
+        let system = LSystem::new(axiom)
+            .rule(a.clone(), Seq::chords(vec![a.clone(), b.clone()]))
+            .rule(b.clone(), Seq::chords(vec![b.clone(), a.clone()]).condition(1, Condition::Fill));
+        let generation = system.generation(1);
+        assert_eq!(generation.get_conditions(), &vec![
+            Condition::Every(1, 2), Condition::Follow, Condition::Always, Condition::Fill,
+        ]);
+        assert!(Arc::ptr_eq(&generation.get_fill(), &fill));
+        // the head position of the axiom isn't carried over
+        assert_eq!(generation.midibox().next().unwrap()[0].tone, Tone::A);
+    }
+}
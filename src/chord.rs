@@ -37,6 +37,31 @@ impl Chord {
         Chord { notes: new_notes }
     }
 
+    /// Splits the chord into `count` evenly spaced retriggers spanning its total duration.
+    ///
+    /// Each note keeps its length relative to the chord, and the velocity ramps linearly so that
+    /// the last retrigger plays at `velocity_ramp` times the velocity of the first.
+    pub fn ratchet(&self, count: u32, velocity_ramp: f64) -> Vec<Chord> {
+        let total = self.total_duration() as u64;
+        if count <= 1 || total == 0 {
+            return vec![self.clone()];
+        }
+        let count = count as u64;
+        (0..count).map(|i| {
+            let part = ((i + 1) * total / count - i * total / count) as u32;
+            let factor = 1.0 + (velocity_ramp - 1.0) * i as f64 / (count - 1) as f64;
+            Chord::new(self.notes.iter().map(|m| {
+                let duration = if m.duration == 0 {
+                    0
+                } else {
+                    ((m.duration as u64 * part as u64 / total) as u32).max(1)
+                };
+                m.set_duration(duration)
+                    .set_velocity((m.velocity as f64 * factor).round().clamp(0.0, 127.0) as u8)
+            }).collect())
+        }).collect()
+    }
+
 }
 
 pub trait ToChord {
@@ -65,6 +90,11 @@ impl MutMidi for Chord {
         self
     }
 
+    fn offset(mut self, offset: f32) -> Self {
+        self.notes = self.notes.into_iter().map(|m| m.set_offset(offset)).collect();
+        self
+    }
+
     fn pitch(mut self, tone: Tone, oct: u8) -> Self {
         self.notes = self.notes.into_iter().map(|m| m.set_pitch(tone, oct)).collect();
         self
@@ -1,23 +1,24 @@
 use std::sync::Arc;
 use crossbeam::atomic::AtomicCell;
 use crate::midi::Midi;
-use crate::Midibox;
+use crate::{Midibox, step_duration};
+use crate::period::{lcm, Period};
 
 // Utility that allows dynamically choosing between one of several midibox
 // instances while playing
 pub struct PickChannel<F> where F: Fn() -> Vec<Box<dyn Midibox>> {
     boxen: Vec<Box<dyn Midibox>>,
-    // TODO: should we expose a reset behavior vs. the always-advance behavior
-    //       implemented here?
+    // rebuilds the boxen when the channel is reset
     reset: F,
     curr_pos: usize,
     curr_box: Arc<AtomicCell<usize>>,
     prev_box: usize,
-    measure_size: usize
+    measure_size: usize,
+    step_length: Option<u32>,
 }
 
 impl <F> PickChannel<F>
-    where F: Fn() -> Vec<Box<dyn Midibox>> + 'static {
+    where F: Fn() -> Vec<Box<dyn Midibox>> + Send + 'static {
     pub fn new(
         measure_size: usize,
         curr_box: Arc<AtomicCell<usize>>,
@@ -30,20 +31,22 @@ impl <F> PickChannel<F>
                 curr_pos: 0,
                 prev_box: curr_box.load(),
                 curr_box,
-                measure_size
+                measure_size,
+                step_length: None,
             }
         )
     }
 }
 
 impl <F> Midibox for PickChannel<F>
-    where F: Fn() -> Vec<Box<dyn Midibox>> {
+    where F: Fn() -> Vec<Box<dyn Midibox>> + Send {
     fn next(&mut self) -> Option<Vec<Midi>> {
         // advance all boxen
         let results: Vec<Option<Vec<Midi>>> = self.boxen.iter_mut()
             .map(|it| it.next())
             .collect();
         let result = results.get(self.prev_box).unwrap_or(&None);
+        self.step_length = self.boxen.get(self.prev_box).and_then(|it| it.step_length());
         self.curr_pos = (self.curr_pos + 1) % self.measure_size;
         let curr = self.curr_box.load();
         if curr != self.prev_box && self.curr_pos == 0 {
@@ -51,4 +54,224 @@ impl <F> Midibox for PickChannel<F>
         }
         result.clone()
     }
-}
\ No newline at end of file
+
+    fn reset(&mut self) {
+        self.boxen = (self.reset)();
+        self.curr_pos = 0;
+        self.prev_box = self.curr_box.load();
+        self.step_length = None;
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        self.step_length
+    }
+}
+
+pub fn stack(boxen: Vec<Box<dyn Midibox>>) -> Box<dyn Midibox> {
+    Stack::wrap(boxen)
+}
+
+pub fn chain(boxen: Vec<(Box<dyn Midibox>, u32)>) -> Box<dyn Midibox> {
+    Chain::wrap(boxen)
+}
+
+/// Plays several midiboxes in parallel on one channel.
+///
+/// Each midibox keeps its own timing: a step of the stack lasts until the next of the midiboxes
+/// is due for its next step, and notes from the others keep ringing over it.
+pub struct Stack {
+    boxen: Vec<Box<dyn Midibox>>,
+    // ticks until each of the boxen is due for its next step
+    waits: Vec<u32>,
+    step_length: u32,
+}
+
+impl Stack {
+    pub fn wrap(boxen: Vec<Box<dyn Midibox>>) -> Box<dyn Midibox> {
+        Box::new(Stack {
+            waits: vec![0; boxen.len()],
+            boxen,
+            step_length: 1,
+        })
+    }
+}
+
+impl Midibox for Stack {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        if self.boxen.is_empty() {
+            return None;
+        }
+        let mut notes: Vec<Midi> = Vec::new();
+        for (midibox, wait) in self.boxen.iter_mut().zip(self.waits.iter_mut()) {
+            if *wait > 0 {
+                continue;
+            }
+            *wait = match midibox.next() {
+                Some(step) => {
+                    let duration = step_duration(midibox.as_ref(), &step);
+                    notes.extend(step);
+                    duration
+                }
+                None => 1,
+            };
+        }
+
+        let step = self.waits.iter().copied().min().unwrap_or(1);
+        self.waits.iter_mut().for_each(|wait| *wait -= step);
+        self.step_length = step;
+        // let midiboxes wrapping the stack see how long the step lasts
+        if notes.iter().all(|n| n.duration < step) {
+            notes.push(Midi::rest().set_duration(step));
+        }
+        Some(notes)
+    }
+
+    fn reset(&mut self) {
+        self.boxen.iter_mut().for_each(|it| it.reset());
+        self.waits.iter_mut().for_each(|wait| *wait = 0);
+        self.step_length = 1;
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        Some(self.step_length)
+    }
+
+    fn period(&self) -> Option<Period> {
+        let periods: Vec<Period> = self.boxen.iter()
+            .map(|it| it.period())
+            .collect::<Option<Vec<Period>>>()?;
+        let ticks = periods.iter().fold(1, |ticks, p| lcm(ticks, p.ticks));
+        // the stack's steps start wherever any of the boxen's steps start
+        let grid = periods.iter().map(|p| p.grid).min()?;
+        Some(Period::of_ticks(ticks, grid))
+    }
+}
+
+/// Plays each midibox for the given number of ticks, then moves on to the next one, looping
+/// back to the first after the last. Each midibox starts over from its first step when its turn
+/// comes, and notes still sounding at the end of a turn are cut short.
+pub struct Chain {
+    boxen: Vec<(Box<dyn Midibox>, u32)>,
+    curr_box: usize,
+    elapsed: u32,
+    step_length: u32,
+}
+
+impl Chain {
+    pub fn wrap(boxen: Vec<(Box<dyn Midibox>, u32)>) -> Box<dyn Midibox> {
+        Box::new(Chain {
+            boxen,
+            curr_box: 0,
+            elapsed: 0,
+            step_length: 1,
+        })
+    }
+}
+
+impl Midibox for Chain {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        if self.boxen.iter().all(|(_, ticks)| *ticks == 0) {
+            return None;
+        }
+        while self.elapsed >= self.boxen[self.curr_box].1 {
+            self.curr_box = (self.curr_box + 1) % self.boxen.len();
+            self.elapsed = 0;
+            self.boxen[self.curr_box].0.reset();
+        }
+
+        let (midibox, ticks) = &mut self.boxen[self.curr_box];
+        let remaining = *ticks - self.elapsed;
+        let notes = midibox.next();
+        let step = notes.as_ref()
+            .map(|it| step_duration(midibox.as_ref(), it))
+            .unwrap_or(1)
+            .min(remaining);
+        self.elapsed += step;
+        self.step_length = step;
+        notes.map(|it| it.into_iter()
+            .map(|n| n.set_duration(n.duration.min(remaining)))
+            .collect())
+    }
+
+    fn reset(&mut self) {
+        self.curr_box = 0;
+        self.elapsed = 0;
+        self.step_length = 1;
+        if let Some((midibox, _)) = self.boxen.first_mut() {
+            midibox.reset()
+        }
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        Some(self.step_length)
+    }
+
+    fn period(&self) -> Option<Period> {
+        let mut ticks: u64 = 0;
+        let mut grid: u64 = 1;
+        for (midibox, turn) in self.boxen.iter().filter(|(_, turn)| *turn > 0) {
+            ticks += *turn as u64;
+            grid = lcm(grid, midibox.period()?.grid);
+        }
+        if ticks == 0 {
+            return None;
+        }
+        // the boxen's grids only line up across the chain if every turn is a multiple of them
+        let aligned = self.boxen.iter()
+            .map(|(_, turn)| *turn as u64 % grid)
+            .all(|misaligned| misaligned == 0);
+        Some(Period::of_ticks(ticks, if aligned { grid } else { ticks }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::composite::{chain, stack};
+    use crate::period::Period;
+    use crate::sequences::Seq;
+    use crate::step_duration;
+    use crate::tone::Tone;
+
+    #[test]
+    fn stack_steps() {
+        let mut stacked = stack(vec![
+            Seq::new(vec![Tone::C.oct(3) * 3]).midibox(),
+            Seq::new(vec![Tone::E.oct(4) * 2]).midibox(),
+        ]);
+        assert_eq!(stacked.period(), Some(Period::of_ticks(6, 2)));
+        let mut starts = vec![];
+        let mut time = 0;
+        while time < 6 {
+            let notes = stacked.next().unwrap();
+            let tones: Vec<Tone> = notes.iter().filter(|n| !n.is_rest()).map(|n| n.tone).collect();
+            starts.push((time, tones));
+            time += step_duration(stacked.as_ref(), &notes);
+        }
+        assert_eq!(starts, vec![
+            (0, vec![Tone::C, Tone::E]),
+            (2, vec![Tone::E]),
+            (3, vec![Tone::C]),
+            (4, vec![Tone::E]),
+        ]);
+    }
+
+    #[test]
+    fn chain_turns() {
+        let mut chained = chain(vec![
+            (Seq::new(vec![Tone::C.oct(4) * 3, Tone::D.oct(4) * 3]).midibox(), 4),
+            (Seq::new(vec![Tone::E.oct(4) * 2]).midibox(), 2),
+        ]);
+        assert_eq!(chained.period(), Some(Period::of_ticks(6, 6)));
+        let steps: Vec<(Tone, u32)> = (0..5)
+            .map(|_| chained.next().unwrap()[0])
+            .map(|n| (n.tone, n.duration))
+            .collect();
+        assert_eq!(steps, vec![
+            (Tone::C, 3),
+            (Tone::D, 1),
+            (Tone::E, 2),
+            (Tone::C, 3),
+            (Tone::D, 1),
+        ]);
+    }
+}
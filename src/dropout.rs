@@ -1,23 +1,55 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+use crossbeam::atomic::AtomicCell;
 use crate::{Map, map_notes, Midibox};
 use rand::Rng;
+use rand::rngs::StdRng;
 use crate::chord::Chord;
 use crate::midi::{Midi, MutMidi};
+use crate::period::Period;
+use crate::rand::{aperiodic, session_rng};
 use crate::tone::Tone;
 
 
 pub fn random_dropout(midibox: Box<dyn Midibox>, p: f64) -> Box<dyn Midibox> {
-    map_notes(midibox, move |m| {
-        if rand::thread_rng().gen_bool(p) {
+    random_dropout_with_rng(midibox, p, session_rng())
+}
+
+pub fn random_dropout_with_rng(midibox: Box<dyn Midibox>, p: f64, rng: StdRng) -> Box<dyn Midibox> {
+    let rng = RefCell::new(rng);
+    aperiodic(map_notes(midibox, move |m| {
+        if rng.borrow_mut().gen_bool(p) {
             m.set_pitch(Tone::Rest, 3)
         } else {
             m
         }
-    })
+    }))
+}
+
+/// Like `random_dropout`, with a probability that can be changed while playing
+pub fn random_dropout_sync(midibox: Box<dyn Midibox>, p: Arc<AtomicCell<f64>>) -> Box<dyn Midibox> {
+    random_dropout_sync_with_rng(midibox, p, session_rng())
+}
+
+pub fn random_dropout_sync_with_rng(
+    midibox: Box<dyn Midibox>,
+    p: Arc<AtomicCell<f64>>,
+    rng: StdRng
+) -> Box<dyn Midibox> {
+    let rng = RefCell::new(rng);
+    aperiodic(map_notes(midibox, move |m| {
+        if rng.borrow_mut().gen_bool(p.load().clamp(0.0, 1.0)) {
+            m.set_pitch(Tone::Rest, 3)
+        } else {
+            m
+        }
+    }))
 }
 
 pub struct Dropout {
     duration: u32,
     duration_seen: u32,
+    started: bool,
     playing: bool,
     midibox: Box<dyn Midibox>,
 }
@@ -26,6 +58,7 @@ impl Dropout {
     pub fn wrap(midibox: Box<dyn Midibox>, duration: u32, playing: bool) -> Box<dyn Midibox> {
         Box::new(Dropout {
             duration,
+            started: playing,
             playing,
             duration_seen: 0,
             midibox
@@ -43,7 +76,8 @@ impl Midibox for Dropout {
         return match to_play {
             Some(notes) => {
                 let to_play_chord = Chord { notes: notes.clone() };
-                self.duration_seen += to_play_chord.total_duration();
+                self.duration_seen += self.midibox.step_length()
+                    .unwrap_or(to_play_chord.total_duration());
                 if self.playing {
                     // forward the notes
                     return Some(notes)
@@ -55,4 +89,27 @@ impl Midibox for Dropout {
             None => None
         };
     }
+
+    fn reset(&mut self) {
+        self.duration_seen = 0;
+        self.playing = self.started;
+        self.midibox.reset()
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        self.midibox.step_length()
+    }
+
+    /// The dropout only repeats predictably if it toggles exactly on step boundaries
+    fn period(&self) -> Option<Period> {
+        let inner = self.midibox.period()?;
+        if self.duration == 0 {
+            return inner.align_steps(2);
+        }
+        let misaligned = self.duration as u64 % inner.grid;
+        if misaligned != 0 {
+            return None;
+        }
+        Some(inner.align_ticks(2 * self.duration as u64))
+    }
 }
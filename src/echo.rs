@@ -0,0 +1,184 @@
+use crate::{Midibox, step_duration};
+use crate::midi::Midi;
+use crate::scale::Interval;
+
+
+/// Describes the echoes a MIDI delay adds to the notes produced by a Midibox.
+///
+/// Each sounding note is repeated `feedback` times, `delay` ticks apart. Every repeat scales the
+/// velocity of the previous one by `decay` and shifts its pitch by the configured interval.
+/// Repeats that would fall outside of the MIDI note range are dropped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Echo {
+    delay: u32,
+    feedback: u32,
+    decay: f64,
+    shift: i16,
+}
+
+impl Echo {
+    pub fn new(delay: u32, feedback: u32) -> Self {
+        Echo { delay: delay.max(1), feedback, decay: 1.0, shift: 0 }
+    }
+
+    /// Velocity factor applied on every repeat
+    pub fn decay(mut self, decay: f64) -> Self {
+        self.decay = decay;
+        self
+    }
+
+    /// Transposes every repeat up by the interval from the previous one
+    pub fn shift_up(mut self, interval: Interval) -> Self {
+        self.shift = interval.steps() as i16;
+        self
+    }
+
+    /// Transposes every repeat down by the interval from the previous one
+    pub fn shift_down(mut self, interval: Interval) -> Self {
+        self.shift = -(interval.steps() as i16);
+        self
+    }
+
+    pub fn wrap(self, midibox: Box<dyn Midibox>) -> Box<dyn Midibox> {
+        Box::new(Delay {
+            echo: self,
+            midibox,
+            time: 0,
+            wait: 0,
+            pending: Vec::new(),
+            step_length: 1,
+        })
+    }
+
+    fn repeat(&self, note: &Midi, repeat: u32) -> Option<Midi> {
+        let pitch = note.u8_maybe()? as i32 + self.shift as i32 * repeat as i32;
+        // octaves below C0 can't be represented
+        if !(12..=127).contains(&pitch) {
+            return None;
+        }
+        let velocity = note.velocity as f64 * self.decay.powi(repeat as i32);
+        let velocity = velocity.round().clamp(0.0, 127.0) as u8;
+        if velocity == 0 {
+            return None;
+        }
+        Some(note.set_pitch_u8(Some(pitch as u8)).set_velocity(velocity))
+    }
+}
+
+pub fn echo(midibox: Box<dyn Midibox>, delay: u32, feedback: u32, decay: f64) -> Box<dyn Midibox> {
+    Echo::new(delay, feedback).decay(decay).wrap(midibox)
+}
+
+/// Plays the notes of a Midibox along with their echoes, see [Echo].
+///
+/// Echoes can start in the middle of a step of the wrapped midibox, so the steps of a delay end
+/// whenever either the wrapped midibox or an echo is due.
+///
+/// A delay has no period: echoes carry over from one loop of the wrapped midibox into the next,
+/// but the first loop has none to carry over, so it never comes around again.
+pub struct Delay {
+    echo: Echo,
+    midibox: Box<dyn Midibox>,
+    // ticks played since the start
+    time: u64,
+    // ticks until the wrapped midibox is due for its next step
+    wait: u32,
+    // echoes yet to play, with the tick they're due on
+    pending: Vec<(u64, Midi)>,
+    step_length: u32,
+}
+
+impl Midibox for Delay {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        let mut notes: Vec<Midi> = Vec::new();
+        if self.wait == 0 {
+            let step = self.midibox.next()?;
+            self.wait = step_duration(self.midibox.as_ref(), &step);
+            for note in step.iter().filter(|n| !n.is_rest() && n.duration > 0) {
+                for repeat in 1..=self.echo.feedback {
+                    if let Some(echo) = self.echo.repeat(note, repeat) {
+                        let due = self.time + (repeat * self.echo.delay) as u64;
+                        self.pending.push((due, echo));
+                    }
+                }
+            }
+            notes.extend(step);
+        }
+
+        let time = self.time;
+        notes.extend(self.pending.iter().filter(|(due, _)| *due == time).map(|(_, n)| *n));
+        self.pending.retain(|(due, _)| *due > time);
+
+        let next_echo = self.pending.iter().map(|(due, _)| (due - time) as u32).min();
+        let step = next_echo.map_or(self.wait, |ticks| ticks.min(self.wait));
+        self.wait -= step;
+        self.time += step as u64;
+        self.step_length = step;
+        // let midiboxes wrapping the delay see how long the step lasts
+        if notes.iter().all(|n| n.duration < step) {
+            notes.push(Midi::rest().set_duration(step));
+        }
+        Some(notes)
+    }
+
+    fn reset(&mut self) {
+        self.time = 0;
+        self.wait = 0;
+        self.pending.clear();
+        self.step_length = 1;
+        self.midibox.reset()
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        Some(self.step_length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::echo::Echo;
+    use crate::scale::Interval;
+    use crate::sequences::Seq;
+    use crate::step_duration;
+    use crate::tone::Tone;
+
+    #[test]
+    fn echoes() {
+        let seq = Seq::new(vec![Tone::C.oct(4) * 4]);
+        let mut delayed = Echo::new(3, 2).decay(0.5).shift_up(Interval::Perf5)
+            .wrap(seq.midibox());
+        let mut played = vec![];
+        let mut time = 0;
+        while time < 8 {
+            let notes = delayed.next().unwrap();
+            for note in notes.iter().filter(|n| !n.is_rest()) {
+                played.push((time, note.tone, note.oct, note.velocity));
+            }
+            time += step_duration(delayed.as_ref(), &notes);
+        }
+        assert_eq!(played, vec![
+            (0, Tone::C, 4, 100),
+            (3, Tone::G, 4, 50),
+            (4, Tone::C, 4, 100),
+            (6, Tone::D, 5, 25),
+            (7, Tone::G, 4, 50),
+        ]);
+    }
+
+    #[test]
+    fn echoes_below_c0() {
+        let seq = Seq::new(vec![Tone::D.oct(0) * 8]);
+        let mut delayed = Echo::new(2, 3).shift_down(Interval::Maj2).wrap(seq.midibox());
+        let mut played = vec![];
+        let mut time = 0;
+        while time < 8 {
+            let notes = delayed.next().unwrap();
+            for note in notes.iter().filter(|n| !n.is_rest()) {
+                played.push((time, note.tone, note.oct));
+            }
+            time += step_duration(delayed.as_ref(), &notes);
+        }
+        // the second echo would fall below C0, and the third further still
+        assert_eq!(played, vec![(0, Tone::D, 0), (2, Tone::C, 0)]);
+    }
+}
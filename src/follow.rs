@@ -0,0 +1,126 @@
+use std::error::Error;
+use std::sync::Arc;
+use crossbeam::atomic::AtomicCell;
+use midir::MidiInputConnection;
+use crate::{map_notes, Midibox};
+use crate::input::{listen, MidiMessage};
+use crate::midi::Midi;
+use crate::scale::{Scale, Snap};
+
+
+/// Follows the last key played on a MIDI input, like the transpose mode of a hardware
+/// sequencer.
+///
+/// The midiboxes wrapped by the follower are shifted by the distance from the reference root to
+/// the last key played, which holds until another key is played. Wrapping only some of the
+/// channels of a player leaves the others alone. Notes shifted outside of the MIDI note range
+/// are turned into rests.
+#[derive(Debug, Clone)]
+pub struct KeyTranspose {
+    root: u8,
+    key: Arc<AtomicCell<u8>>,
+    channel: Option<u8>,
+}
+
+impl KeyTranspose {
+    pub fn new(root: Midi) -> Self {
+        let root = root.u8_maybe().unwrap_or(60);
+        KeyTranspose { root, key: Arc::new(AtomicCell::new(root)), channel: None }
+    }
+
+    /// Only follows the keys played on the given input MIDI channel
+    pub fn channel(mut self, channel: u8) -> Self {
+        self.channel = Some(channel);
+        self
+    }
+
+    pub fn handle(&self, message: MidiMessage) {
+        if let MidiMessage::NoteOn { channel, key, .. } = message {
+            let followed = match self.channel {
+                Some(c) => c == channel,
+                None => true,
+            };
+            // octaves below C0 can't be represented
+            if followed && (12..=127).contains(&key) {
+                self.key.store(key)
+            }
+        }
+    }
+
+    /// Follows the keys played on the MIDI input port with the given index, for as long as the
+    /// returned connection is kept alive
+    pub fn listen(&self, port_id: usize) -> Result<MidiInputConnection<()>, Box<dyn Error>> {
+        let follower = self.clone();
+        listen(port_id, move |message| follower.handle(message))
+    }
+
+    /// The semitones from the root to the last key played
+    pub fn semitones(&self) -> i16 {
+        self.key.load() as i16 - self.root as i16
+    }
+
+    /// Shifts the notes of the midibox chromatically
+    pub fn transpose(&self, midibox: Box<dyn Midibox>) -> Box<dyn Midibox> {
+        let follower = self.clone();
+        map_notes(midibox, move |n| shift(n, follower.semitones()))
+    }
+
+    /// Shifts the notes of the midibox by the scale degrees from the root to the last key
+    /// played, so that the notes stay in the scale. Keys outside of the scale count as the
+    /// nearest tone of the scale, and notes outside of the scale are shifted chromatically.
+    pub fn harmonize(&self, midibox: Box<dyn Midibox>, scale: Scale) -> Box<dyn Midibox> {
+        let follower = self.clone();
+        // the tones of the scale across the MIDI note range, from lowest to highest
+        let ladder: Vec<u8> = scale.range(0, 9).iter().filter_map(|n| n.u8_maybe()).collect();
+        let scale_ladder = ladder.clone();
+        let degree = move |v: u8| {
+            let snapped = scale.quantize(Midi::from(v), Snap::Nearest);
+            snapped.u8_maybe().and_then(|s| scale_ladder.binary_search(&s).ok())
+        };
+        map_notes(midibox, move |n| {
+            let position = n.u8_maybe().and_then(|v| ladder.binary_search(&v).ok());
+            match (position, degree(follower.key.load()), degree(follower.root)) {
+                (Some(position), Some(key), Some(root)) => {
+                    let moved = position as i64 + key as i64 - root as i64;
+                    let target = usize::try_from(moved).ok().and_then(|i| ladder.get(i));
+                    n.set_pitch_u8(target.copied())
+                }
+                _ => shift(n, follower.semitones()),
+            }
+        })
+    }
+}
+
+fn shift(note: Midi, semitones: i16) -> Midi {
+    if semitones == 0 {
+        return note;
+    }
+    let shifted = note.u8_maybe().map(|v| v as i16 + semitones);
+    note.set_pitch_u8(shifted.filter(|v| (12..=127).contains(v)).map(|v| v as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::follow::KeyTranspose;
+    use crate::input::MidiMessage;
+    use crate::scale::Scale;
+    use crate::sequences::Seq;
+    use crate::tone::Tone;
+
+    #[test]
+    fn follows_last_key() {
+        let follower = KeyTranspose::new(Tone::C.oct(3)).channel(1);
+        let seq = Seq::new(vec![Tone::C.oct(4), Tone::E.oct(4)]);
+        let mut chromatic = follower.transpose(seq.midibox());
+        let mut diatonic = follower.harmonize(seq.midibox(), Scale::major(Tone::C));
+        assert_eq!(chromatic.next().unwrap()[0], Tone::C.oct(4));
+
+        follower.handle(MidiMessage::NoteOn { channel: 1, key: 50, velocity: 100 });
+        follower.handle(MidiMessage::NoteOn { channel: 2, key: 55, velocity: 100 });
+        assert_eq!(follower.semitones(), 2);
+        assert_eq!(chromatic.next().unwrap()[0].tone, Tone::Gb);
+        // E moves up to F, a second up in the scale
+        assert_eq!(diatonic.next().unwrap()[0].tone, Tone::D);
+        assert_eq!(diatonic.next().unwrap()[0].tone, Tone::F);
+    }
+}
@@ -0,0 +1,258 @@
+use crate::midi::{Midi, MutMidi};
+use crate::Midibox;
+use crate::period::{lcm, Period};
+use crate::sequences::Seq;
+
+
+/// Describes how notes starting on a grid of `grid` ticks are shifted in time and scaled in
+/// velocity. Slot `i` of the template applies to notes starting on grid step `i`, and the
+/// template repeats every `offsets.len()` grid steps. Notes that don't start on the grid are
+/// left alone. Offsets are measured in ticks and may be fractional.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GrooveTemplate {
+    grid: u32,
+    offsets: Vec<f32>,
+    velocities: Vec<f64>,
+}
+
+impl GrooveTemplate {
+    pub fn new(grid: u32, offsets: Vec<f32>, velocities: Vec<f64>) -> Self {
+        GrooveTemplate { grid, offsets, velocities }
+    }
+
+    /// MPC-style swing: every second step of `grid` ticks is delayed. `percent` ranges from 50
+    /// (straight) to 75 (dotted feel).
+    pub fn swing(grid: u32, percent: u8) -> Self {
+        let percent = percent.clamp(50, 75) as f32;
+        let delay = 2.0 * grid as f32 * (percent - 50.0) / 100.0;
+        GrooveTemplate::new(grid, vec![0.0, delay], vec![1.0, 1.0])
+    }
+
+    /// Learns a template spanning `steps` grid steps from a played sequence. Each chord is
+    /// matched to the nearest step of the grid, and the template records the average distance
+    /// from the grid and the average velocity relative to the whole sequence for each slot.
+    pub fn extract(seq: &Seq, grid: u32, steps: usize) -> Self {
+        let mut offsets: Vec<Vec<f32>> = vec![vec![]; steps];
+        let mut velocities: Vec<Vec<f64>> = vec![vec![]; steps];
+        let mut time: u64 = 0;
+        for chord in seq.get_chords() {
+            let sounding: Vec<&Midi> = chord.notes.iter().filter(|n| !n.is_rest()).collect();
+            if !sounding.is_empty() && grid > 0 && steps > 0 {
+                let grid_step = (time + grid as u64 / 2) / grid as u64;
+                let slot = grid_step as usize % steps;
+                offsets[slot].push((time as i64 - (grid_step * grid as u64) as i64) as f32);
+                velocities[slot].extend(sounding.iter().map(|n| n.velocity as f64));
+            }
+            time += chord.total_duration() as u64;
+        }
+
+        let all: Vec<f64> = velocities.iter().flatten().copied().collect();
+        let average = if all.is_empty() { 0.0 } else { all.iter().sum::<f64>() / all.len() as f64 };
+        GrooveTemplate::new(
+            grid,
+            offsets.iter()
+                .map(|o| if o.is_empty() { 0.0 } else { o.iter().sum::<f32>() / o.len() as f32 })
+                .collect(),
+            velocities.iter()
+                .map(|v| if v.is_empty() || average == 0.0 {
+                    1.0
+                } else {
+                    (v.iter().sum::<f64>() / v.len() as f64) / average
+                })
+                .collect(),
+        )
+    }
+
+    /// Returns the offset in ticks for a note starting at the given time
+    pub fn offset_at(&self, tick: u64) -> f32 {
+        self.slot(tick, &self.offsets).unwrap_or(0.0)
+    }
+
+    /// Returns the velocity factor for a note starting at the given time
+    pub fn velocity_at(&self, tick: u64) -> f64 {
+        self.slot(tick, &self.velocities).unwrap_or(1.0)
+    }
+
+    /// The number of ticks before the template repeats
+    pub fn period(&self) -> u64 {
+        self.grid as u64 * lcm(self.offsets.len() as u64, self.velocities.len() as u64)
+    }
+
+    fn slot<T: Copy>(&self, tick: u64, values: &[T]) -> Option<T> {
+        if self.grid == 0 || values.is_empty() {
+            return None;
+        }
+        let (step, remainder) = (tick / self.grid as u64, tick % self.grid as u64);
+        if remainder != 0 {
+            return None;
+        }
+        values.get(step as usize % values.len()).copied()
+    }
+}
+
+pub fn swing(midibox: Box<dyn Midibox>, grid: u32, percent: u8) -> Box<dyn Midibox> {
+    Groove::wrap(midibox, GrooveTemplate::swing(grid, percent))
+}
+
+/// Applies a groove template to the notes produced by a Midibox.
+///
+/// Notes are moved by shortening or lengthening the preceding step, so the overall length of the
+/// wrapped sequence is preserved. A rest is inserted if the very first note is delayed. The
+/// fractional part of an offset is carried by the note's sub-tick offset.
+pub struct Groove {
+    template: GrooveTemplate,
+    midibox: Box<dyn Midibox>,
+    // when the next step of the wrapped midibox would have started, in ticks
+    nominal_time: u64,
+    // when the next step we emit will start, in ticks
+    output_time: u64,
+    // notes held back while a leading rest plays, with the length of their step
+    pending: Option<(Vec<Midi>, u32)>,
+    // the length of the last step we emitted
+    step_length: u32,
+}
+
+impl Groove {
+    pub fn wrap(midibox: Box<dyn Midibox>, template: GrooveTemplate) -> Box<dyn Midibox> {
+        Box::new(Groove {
+            template,
+            midibox,
+            nominal_time: 0,
+            output_time: 0,
+            pending: None,
+            step_length: 1,
+        })
+    }
+
+    fn shifted_time(&self, nominal: u64) -> f64 {
+        nominal as f64 + self.template.offset_at(nominal) as f64
+    }
+}
+
+impl Midibox for Groove {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        if let Some((notes, step)) = self.pending.take() {
+            self.step_length = step;
+            return Some(notes);
+        }
+
+        let notes = self.midibox.next()?;
+        let longest = notes.iter().map(|n| n.duration).max().unwrap_or(0);
+        let step = self.midibox.step_length().unwrap_or(longest);
+        if step == 0 {
+            self.step_length = 1;
+            return Some(notes);
+        }
+
+        let nominal_start = self.nominal_time;
+        let shifted_start = self.shifted_time(nominal_start).max(self.output_time as f64);
+        let start = shifted_start.floor() as u64;
+        let start_offset = (shifted_start - start as f64) as f32;
+        self.nominal_time += step as u64;
+        let end = (self.shifted_time(self.nominal_time).floor() as u64).max(start + 1);
+        let stretch = (end - start) as i64 - step as i64;
+        let factor = self.template.velocity_at(nominal_start);
+
+        let grooved: Vec<Midi> = notes.into_iter()
+            .map(|n| if n.duration == 0 {
+                n
+            } else {
+                n.set_duration((n.duration as i64 + stretch).max(1) as u32)
+                    .set_velocity((n.velocity as f64 * factor).round().clamp(0.0, 127.0) as u8)
+                    .set_offset(n.offset + start_offset)
+            })
+            .collect();
+
+        let delay = start - self.output_time;
+        self.output_time = end;
+        if delay > 0 {
+            self.pending = Some((grooved, (end - start) as u32));
+            self.step_length = delay as u32;
+            Some(vec![Midi::rest().set_duration(delay as u32)])
+        } else {
+            self.step_length = (end - start) as u32;
+            Some(grooved)
+        }
+    }
+
+    fn reset(&mut self) {
+        self.nominal_time = 0;
+        self.output_time = 0;
+        self.pending = None;
+        self.step_length = 1;
+        self.midibox.reset()
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        Some(self.step_length)
+    }
+
+    fn period(&self) -> Option<Period> {
+        let period = self.midibox.period()?.align_ticks(self.template.period());
+        // shifted notes no longer line up with the wrapped midibox's grid
+        Some(Period { grid: period.ticks, ..period })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::groove::{Groove, GrooveTemplate};
+    use crate::sequences::Seq;
+    use crate::tone::Tone;
+
+    #[test]
+    fn swing() {
+        let seq = Seq::new(vec![
+            Tone::C.oct(4) * 12,
+            Tone::D.oct(4) * 12,
+            Tone::E.oct(4) * 12,
+            Tone::F.oct(4) * 12,
+        ]);
+        let mut swung = Groove::wrap(seq.midibox(), GrooveTemplate::swing(12, 75));
+        let durations: Vec<u32> = (0..4)
+            .map(|_| swung.next().unwrap()[0].duration)
+            .collect();
+        assert_eq!(durations, vec![18, 6, 18, 6]);
+    }
+
+    #[test]
+    fn sub_tick_swing() {
+        let seq = Seq::new(vec![Tone::C.oct(4), Tone::D.oct(4)]);
+        let mut swung = Groove::wrap(seq.midibox(), GrooveTemplate::swing(1, 75));
+        let first = swung.next().unwrap()[0];
+        assert_eq!((first.duration, first.offset), (1, 0.0));
+        let second = swung.next().unwrap()[0];
+        assert_eq!((second.duration, second.offset), (1, 0.5));
+    }
+
+    #[test]
+    fn delayed_first_note() {
+        let seq = Seq::new(vec![Tone::C.oct(4) * 12, Tone::D.oct(4) * 12]);
+        let template = GrooveTemplate::new(12, vec![3.0, 0.0], vec![0.5, 1.0]);
+        let mut grooved = Groove::wrap(seq.midibox(), template);
+        let rest = grooved.next().unwrap()[0];
+        assert!(rest.is_rest());
+        assert_eq!(rest.duration, 3);
+        let first = grooved.next().unwrap()[0];
+        assert_eq!(first.tone, Tone::C);
+        assert_eq!(first.duration, 9);
+        assert_eq!(first.velocity, 50);
+        assert_eq!(grooved.next().unwrap()[0].duration, 15);
+    }
+
+    #[test]
+    fn extract() {
+        let seq = Seq::new(vec![
+            Tone::C.oct(4) * 10,
+            Tone::D.oct(4).set_velocity(50) * 14,
+            Tone::E.oct(4) * 10,
+            Tone::F.oct(4).set_velocity(50) * 14,
+        ]);
+        let template = GrooveTemplate::extract(&seq, 12, 2);
+        assert_eq!(template.offset_at(0), 0.0);
+        assert_eq!(template.offset_at(12), -2.0);
+        assert_eq!(template.velocity_at(0), 100.0 / 75.0);
+        assert_eq!(template.velocity_at(12), 50.0 / 75.0);
+        assert_eq!(template.offset_at(5), 0.0);
+    }
+}
@@ -0,0 +1,196 @@
+use std::error::Error;
+use log::info;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use crate::chord::Chord;
+use crate::midi::{Midi, NOTE_OFF_MSG, NOTE_ON_MSG};
+
+pub const CONTROL_CHANGE_MSG: u8 = 0xB0;
+
+/// A message received on a MIDI input port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOn { channel: u8, key: u8, velocity: u8 },
+    NoteOff { channel: u8, key: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    Other,
+}
+
+impl MidiMessage {
+    pub fn parse(bytes: &[u8]) -> Self {
+        match bytes {
+            [status, key, velocity, ..] if status & 0xF0 == NOTE_ON_MSG && *velocity > 0 => {
+                MidiMessage::NoteOn { channel: status & 0x0F, key: *key, velocity: *velocity }
+            }
+            // a note on without velocity is a note off
+            [status, key, _, ..] if status & 0xF0 == NOTE_ON_MSG || status & 0xF0 == NOTE_OFF_MSG => {
+                MidiMessage::NoteOff { channel: status & 0x0F, key: *key }
+            }
+            [status, controller, value, ..] if status & 0xF0 == CONTROL_CHANGE_MSG => {
+                MidiMessage::ControlChange {
+                    channel: status & 0x0F,
+                    controller: *controller,
+                    value: *value,
+                }
+            }
+            _ => MidiMessage::Other,
+        }
+    }
+}
+
+/// Listens on the MIDI input port with the given index, calling `callback` with every message
+/// received. Messages are received for as long as the returned connection is kept alive.
+pub fn listen<F>(port_id: usize, mut callback: F) -> Result<MidiInputConnection<()>, Box<dyn Error>>
+    where F: FnMut(MidiMessage) + Send + 'static
+{
+    let mut midi_in = MidiInput::new("Midi Inputs")?;
+    midi_in.ignore(Ignore::All);
+    let in_ports = midi_in.ports();
+    for (i, p) in in_ports.iter().enumerate() {
+        info!("{}: {}", i, midi_in.port_name(p).unwrap());
+    }
+
+    let port = in_ports.get(port_id)
+        .ok_or_else(|| format!("Missing midi input port {}", port_id))?;
+    let port_name = format!("midibox input {}", port_id);
+    let conn = midi_in.connect(port, &port_name, move |_, bytes, _| {
+        callback(MidiMessage::parse(bytes))
+    }, ())?;
+    Ok(conn)
+}
+
+/// How the keys played on an input make up a chord
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyMode {
+    /// The chord is made of the keys currently held down
+    Held,
+    /// The chord is kept after the keys are released, until a new key is pressed
+    Latch,
+    /// Pressing a key toggles it in or out of the chord, whether or not any keys are held, so
+    /// the chord is built up one key at a time
+    Toggle,
+}
+
+/// Tracks the keys played on an input, in the order that they were pressed.
+#[derive(Debug, Clone)]
+pub struct HeldKeys {
+    mode: KeyMode,
+    // keys currently held down
+    held: Vec<u8>,
+    chord: Vec<Midi>,
+}
+
+impl HeldKeys {
+    pub fn new(mode: KeyMode) -> Self {
+        HeldKeys { mode, held: Vec::new(), chord: Vec::new() }
+    }
+
+    pub fn set_mode(&mut self, mode: KeyMode) {
+        if mode == KeyMode::Held {
+            let held = &self.held;
+            self.chord.retain(|n| n.u8_maybe().is_some_and(|v| held.contains(&v)));
+        }
+        self.mode = mode;
+    }
+
+    pub fn press(&mut self, key: u8, velocity: u8) {
+        // octaves below C0 can't be represented
+        if !(12..=127).contains(&key) {
+            return;
+        }
+        let in_chord = self.chord.iter().position(|n| n.u8_maybe() == Some(key));
+        match self.mode {
+            KeyMode::Held => {}
+            KeyMode::Latch => {
+                if self.held.is_empty() {
+                    self.chord.clear();
+                }
+            }
+            KeyMode::Toggle => {
+                if let Some(position) = in_chord {
+                    self.chord.remove(position);
+                    self.held.push(key);
+                    return;
+                }
+            }
+        }
+        self.held.push(key);
+        self.chord.retain(|n| n.u8_maybe() != Some(key));
+        self.chord.push(Midi::from(key).set_velocity(velocity));
+    }
+
+    pub fn release(&mut self, key: u8) {
+        self.held.retain(|k| *k != key);
+        if self.mode == KeyMode::Held {
+            self.chord.retain(|n| n.u8_maybe() != Some(key));
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.held.clear();
+        self.chord.clear();
+    }
+
+    pub fn handle(&mut self, message: MidiMessage) {
+        match message {
+            MidiMessage::NoteOn { key, velocity, .. } => self.press(key, velocity),
+            MidiMessage::NoteOff { key, .. } => self.release(key),
+            _ => {}
+        }
+    }
+
+    /// The chord played, with notes in the order they were pressed
+    pub fn chord(&self) -> Chord {
+        Chord::new(self.chord.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::input::{HeldKeys, KeyMode, MidiMessage};
+    use crate::tone::Tone;
+
+    fn tones(keys: &HeldKeys) -> Vec<Tone> {
+        keys.chord().notes.iter().map(|n| n.tone).collect()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            MidiMessage::parse(&[0x91, 60, 100]),
+            MidiMessage::NoteOn { channel: 1, key: 60, velocity: 100 }
+        );
+        assert_eq!(MidiMessage::parse(&[0x90, 60, 0]), MidiMessage::NoteOff { channel: 0, key: 60 });
+        assert_eq!(
+            MidiMessage::parse(&[0xB2, 7, 64]),
+            MidiMessage::ControlChange { channel: 2, controller: 7, value: 64 }
+        );
+    }
+
+    #[test]
+    fn key_modes() {
+        let mut keys = HeldKeys::new(KeyMode::Latch);
+        keys.press(64, 100);
+        keys.press(60, 100);
+        keys.release(64);
+        keys.release(60);
+        assert_eq!(tones(&keys), vec![Tone::E, Tone::C]);
+        keys.press(67, 100);
+        assert_eq!(tones(&keys), vec![Tone::G]);
+
+        keys.set_mode(KeyMode::Toggle);
+        keys.release(67);
+        keys.press(60, 100);
+        keys.release(60);
+        keys.press(67, 100);
+        assert_eq!(tones(&keys), vec![Tone::C]);
+        keys.release(67);
+        keys.press(64, 100);
+        keys.press(60, 100);
+        assert_eq!(tones(&keys), vec![Tone::E]);
+        keys.release(64);
+        keys.release(60);
+
+        keys.set_mode(KeyMode::Held);
+        assert_eq!(tones(&keys), vec![]);
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use crossbeam::atomic::AtomicCell;
+use midir::MidiInputConnection;
+use crate::input::{listen, MidiMessage};
+
+
+/// A value that a MIDI controller can be scaled to
+pub trait ParamValue: Copy + Send + Sync + 'static {
+    fn to_f64(self) -> f64;
+    fn from_f64(value: f64) -> Self;
+}
+
+impl ParamValue for f64 {
+    fn to_f64(self) -> f64 {
+        self
+    }
+
+    fn from_f64(value: f64) -> Self {
+        value
+    }
+}
+
+macro_rules! integer_param_value {
+    ($($t:ty),*) => {
+        $(
+            impl ParamValue for $t {
+                fn to_f64(self) -> f64 {
+                    self as f64
+                }
+
+                fn from_f64(value: f64) -> Self {
+                    value.round() as $t
+                }
+            }
+        )*
+    };
+}
+
+integer_param_value!(u8, u32, u64, usize, i32);
+
+/// Sets a cell from controller values scaled to a range
+struct Param {
+    set: Box<dyn Fn(u8) + Send>,
+}
+
+impl Param {
+    fn new<T>(cell: Arc<AtomicCell<T>>, min: T, max: T) -> Self where T: ParamValue {
+        let (min, max) = (min.to_f64(), max.to_f64());
+        Param {
+            set: Box::new(move |value| {
+                cell.store(T::from_f64(min + (max - min) * value.min(127) as f64 / 127.0))
+            }),
+        }
+    }
+}
+
+/// A registry of the parameters that can be changed while playing, such as the tempo of a
+/// `SyncBpm` or the selection of a `PickChannel`, by name.
+///
+/// Parameters are bound to MIDI control changes, either directly or by learning: after `learn`
+/// is called with the name of a parameter, the next control change received is bound to it.
+/// Controller values from 0 to 127 are scaled to the range of the parameter.
+pub struct Params {
+    params: BTreeMap<String, Param>,
+    // the input MIDI channel and controller bound to each parameter
+    bindings: BTreeMap<String, (u8, u8)>,
+    learning: Option<String>,
+}
+
+impl Params {
+    pub fn new() -> Self {
+        Params { params: BTreeMap::new(), bindings: BTreeMap::new(), learning: None }
+    }
+
+    /// Registers a cell as a parameter, set to between `min` and `max` by its controller.
+    /// `max` may be below `min` to invert the controller.
+    pub fn param<T>(mut self, name: &str, cell: Arc<AtomicCell<T>>, min: T, max: T) -> Self
+        where T: ParamValue
+    {
+        self.params.insert(name.to_string(), Param::new(cell, min, max));
+        self
+    }
+
+    /// Binds the next control change received to the parameter
+    pub fn learn(&mut self, name: &str) {
+        self.learning = Some(name.to_string());
+    }
+
+    pub fn cancel_learn(&mut self) {
+        self.learning = None;
+    }
+
+    /// The parameter waiting for a control change to be bound to, if any
+    pub fn learning(&self) -> Option<&str> {
+        self.learning.as_deref()
+    }
+
+    /// Binds a controller on an input MIDI channel to the parameter, in place of its previous
+    /// binding. A controller controls a single parameter, so any other parameter bound to the
+    /// controller is unbound.
+    pub fn bind(&mut self, name: &str, channel: u8, controller: u8) {
+        self.bindings.retain(|_, binding| *binding != (channel, controller));
+        self.bindings.insert(name.to_string(), (channel, controller));
+    }
+
+    pub fn unbind(&mut self, name: &str) {
+        self.bindings.remove(name);
+    }
+
+    /// The input MIDI channel and controller bound to the parameter
+    pub fn binding(&self, name: &str) -> Option<(u8, u8)> {
+        self.bindings.get(name).copied()
+    }
+
+    pub fn handle(&mut self, message: MidiMessage) {
+        if let MidiMessage::ControlChange { channel, controller, value } = message {
+            if let Some(name) = self.learning.take() {
+                self.bind(&name, channel, controller);
+            }
+            for (name, _) in self.bindings.iter().filter(|(_, b)| **b == (channel, controller)) {
+                if let Some(param) = self.params.get(name) {
+                    (param.set)(value)
+                }
+            }
+        }
+    }
+
+    /// Writes the bindings, one `<name> <channel> <controller>` line per parameter
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), Box<dyn Error>> {
+        for (name, (channel, controller)) in self.bindings.iter() {
+            writeln!(writer, "{} {} {}", name, channel, controller)?;
+        }
+        Ok(())
+    }
+
+    /// Reads bindings written by `write`, replacing the bindings of the parameters read.
+    /// Bindings are kept for parameters that aren't registered yet.
+    pub fn read<R: Read>(&mut self, reader: R) -> Result<(), Box<dyn Error>> {
+        for line in BufReader::new(reader).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // names may contain spaces, so the numbers are read from the end of the line
+            let mut fields = line.trim().rsplitn(3, ' ');
+            let controller: u8 = fields.next().ok_or("Missing controller")?.parse()?;
+            let channel: u8 = fields.next().ok_or("Missing channel")?.parse()?;
+            let name = fields.next().ok_or_else(|| format!("Missing name in '{}'", line))?;
+            self.bind(name, channel, controller);
+        }
+        Ok(())
+    }
+
+    /// Writes the bindings to a file, see `write`
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn Error>> {
+        self.write(File::create(path)?)
+    }
+
+    /// Reads bindings from a file written by `save`, see `read`
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Box<dyn Error>> {
+        self.read(File::open(path)?)
+    }
+
+    /// Sets the parameters from the control changes received on the MIDI input port with the
+    /// given index, for as long as the returned connection is kept alive
+    pub fn listen(
+        params: Arc<Mutex<Params>>,
+        port_id: usize
+    ) -> Result<MidiInputConnection<()>, Box<dyn Error>> {
+        listen(port_id, move |message| params.lock().unwrap().handle(message))
+    }
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use crossbeam::atomic::AtomicCell;
+    use crate::input::MidiMessage;
+    use crate::learn::Params;
+
+    fn control(controller: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange { channel: 0, controller, value }
+    }
+
+    #[test]
+    fn learn_and_scale() {
+        let tempo = Arc::new(AtomicCell::new(120_u32));
+        let dropout = Arc::new(AtomicCell::new(0.0));
+        let mut params = Params::new()
+            .param("tempo", tempo.clone(), 60, 180)
+            .param("dropout probability", dropout.clone(), 1.0, 0.0);
+
+        params.handle(control(7, 127));
+        assert_eq!(tempo.load(), 120);
+
+        params.learn("tempo");
+        params.handle(control(7, 127));
+        assert_eq!(tempo.load(), 180);
+        params.handle(control(7, 0));
+        assert_eq!(tempo.load(), 60);
+
+        params.bind("dropout probability", 0, 8);
+        params.handle(control(8, 127));
+        assert_eq!(dropout.load(), 0.0);
+        // taking over the controller of another parameter
+        params.learn("dropout probability");
+        params.handle(control(7, 0));
+        assert_eq!(dropout.load(), 1.0);
+        assert_eq!(tempo.load(), 60);
+        assert_eq!(params.binding("tempo"), None);
+
+        let mut saved: Vec<u8> = vec![];
+        params.bind("tempo", 2, 9);
+        params.write(&mut saved).unwrap();
+        let text = String::from_utf8(saved.clone()).unwrap();
+        assert_eq!(text, "dropout probability 0 7\ntempo 2 9\n");
+        let mut loaded = Params::new();
+        loaded.read(Cursor::new(saved)).unwrap();
+        assert_eq!(loaded.binding("dropout probability"), Some((0, 7)));
+        assert_eq!(loaded.binding("tempo"), Some((2, 9)));
+        assert!(loaded.read("tempo 9".as_bytes()).is_err());
+    }
+}
@@ -2,44 +2,103 @@ use midi::{Midi};
 use crate::chord::Chord;
 use crate::dropout::Dropout;
 use crate::map::{Map, MapBeat, MapChord};
+use crate::period::Period;
 use crate::scale::Interval::Perf5;
 
+pub mod automata;
 pub mod composite;
 pub mod sequences;
 pub mod router;
 pub mod dropout;
+pub mod echo;
+pub mod follow;
+pub mod groove;
+pub mod input;
+pub mod learn;
 pub mod drumlogue;
 pub mod rand;
+pub mod record;
 pub mod arp;
 pub mod midi;
 pub mod player;
 pub mod chord;
 pub mod meter;
 pub mod map;
+pub mod markov;
+pub mod pattern;
+pub mod period;
+pub mod quantize;
 pub mod scale;
+pub mod thru;
 pub mod tone;
+pub mod turing;
+pub mod walk;
 
-pub trait Midibox {
+/// A source of notes. Midiboxes are `Send` so that they can be built on one thread and played
+/// on another.
+pub trait Midibox: Send {
     fn next(&mut self) -> Option<Vec<Midi>>;
+
+    /// Restarts the midibox from its first step. Midiboxes without a notion of a start ignore
+    /// this.
+    fn reset(&mut self) {}
+
+    /// Restarts the midibox and skips ahead to the first step starting at or after `tick` ticks.
+    ///
+    /// By default this plays through the midibox until `tick` is reached. A step without any
+    /// notes takes a single tick, as it does in the player.
+    fn seek(&mut self, tick: u64) {
+        self.reset();
+        let mut elapsed: u64 = 0;
+        while elapsed < tick {
+            match self.next() {
+                Some(notes) => {
+                    let longest = notes.iter().map(|n| n.duration).max().unwrap_or(0);
+                    elapsed += self.step_length().unwrap_or(longest).max(1) as u64
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// The number of ticks until the midibox wants to be polled again after the last call to
+    /// `next`, or `None` to wait until the longest of the returned notes has finished playing.
+    /// Midiboxes that let notes ring over their following steps, like `Stack`, override this.
+    fn step_length(&self) -> Option<u32> {
+        None
+    }
+
+    /// Describes when the midibox repeats itself, or `None` if it never does or can't tell, as
+    /// is the case for random midiboxes.
+    fn period(&self) -> Option<Period> {
+        None
+    }
+}
+
+/// The number of ticks that a step just returned by `midibox` lasts in the player. As with
+/// `Midibox::seek`, a step without any notes takes a single tick.
+pub fn step_duration(midibox: &dyn Midibox, notes: &[Midi]) -> u32 {
+    let longest = notes.iter().map(|n| n.duration).max().unwrap_or(0);
+    midibox.step_length().unwrap_or(longest).max(1)
 }
 
 
 // Common utility functions:
 
 pub fn map_notes<F>(around: Box<dyn Midibox>, f: F) -> Box<dyn Midibox>
-    where F: Fn(Midi) -> Midi + 'static
+    where F: Fn(Midi) -> Midi + Send + 'static
 {
     Map::wrap(around, f)
 }
 
 pub fn map_chords<F>(around: Box<dyn Midibox>, f: F) -> Box<dyn Midibox>
-    where F: Fn(Chord) -> Chord + 'static
+    where F: Fn(Chord) -> Chord + Send + 'static
 {
     MapChord::wrap(around, f)
 }
 
 pub fn map_beat<F>(around: Box<dyn Midibox>, max_beat: usize, f: F) -> Box<dyn Midibox>
-    where F: Fn(Midi, usize) -> Midi + 'static
+    where F: Fn(Midi, usize) -> Midi + Send + 'static
 {
     MapBeat::wrap(around, max_beat, f)
 }
\ No newline at end of file
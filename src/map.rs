@@ -1,6 +1,7 @@
 use crate::chord::Chord;
 use crate::midi::Midi;
 use crate::Midibox;
+use crate::period::Period;
 
 
 /// Maps a function over individual note produced by a Midibox
@@ -12,7 +13,7 @@ pub struct Map<T>
 }
 
 impl<F> Map<F>
-where F: Fn(Midi) -> Midi + 'static
+where F: Fn(Midi) -> Midi + Send + 'static
 {
     pub fn wrap(midibox: Box<dyn Midibox>, mapper: F) -> Box<dyn Midibox> {
         Box::new(Map { mapper, midibox })
@@ -20,13 +21,30 @@ where F: Fn(Midi) -> Midi + 'static
 }
 
 impl <F> Midibox for Map<F>
-where F: Fn(Midi) -> Midi {
+where F: Fn(Midi) -> Midi + Send {
     fn next(&mut self) -> Option<Vec<Midi>> {
         self.midibox.next()
             .map(|it|
                 it.into_iter().map(|note| (self.mapper)(note)).collect::<Vec<Midi>>()
             )
     }
+
+    fn reset(&mut self) {
+        self.midibox.reset()
+    }
+
+    fn seek(&mut self, tick: u64) {
+        self.midibox.seek(tick)
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        self.midibox.step_length()
+    }
+
+    /// Assumes that the mapper doesn't change the duration of notes
+    fn period(&self) -> Option<Period> {
+        self.midibox.period()
+    }
 }
 
 /// Maps a function over groups of simultaneous notes produced by a Midibox
@@ -38,17 +56,34 @@ pub struct MapChord<T>
 }
 
 impl<F> MapChord<F>
-where F: Fn(Chord) -> Chord + 'static {
+where F: Fn(Chord) -> Chord + Send + 'static {
     pub fn wrap(midibox: Box<dyn Midibox>, mapper: F) -> Box<dyn Midibox> {
         Box::new(MapChord { mapper, midibox })
     }
 }
 
 impl <F> Midibox for MapChord<F>
-where F: Fn(Chord) -> Chord {
+where F: Fn(Chord) -> Chord + Send {
     fn next(&mut self) -> Option<Vec<Midi>> {
         self.midibox.next().map(|it| (self.mapper)(Chord::new(it)).notes)
     }
+
+    fn reset(&mut self) {
+        self.midibox.reset()
+    }
+
+    fn seek(&mut self, tick: u64) {
+        self.midibox.seek(tick)
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        self.midibox.step_length()
+    }
+
+    /// Assumes that the mapper doesn't change the duration of notes
+    fn period(&self) -> Option<Period> {
+        self.midibox.period()
+    }
 }
 
 pub struct MapBeat<T>
@@ -61,7 +96,7 @@ pub struct MapBeat<T>
 }
 
 impl<F> MapBeat<F>
-    where F: Fn(Midi, usize) -> Midi + 'static
+    where F: Fn(Midi, usize) -> Midi + Send + 'static
 {
     pub fn wrap(midibox: Box<dyn Midibox>, max_beat: usize, mapper: F) -> Box<dyn Midibox> {
         Box::new(MapBeat { mapper, curr_beat: 0, max_beat, midibox })
@@ -69,7 +104,7 @@ impl<F> MapBeat<F>
 }
 
 impl <F> Midibox for MapBeat<F>
-    where F: Fn(Midi, usize) -> Midi {
+    where F: Fn(Midi, usize) -> Midi + Send {
     fn next(&mut self) -> Option<Vec<Midi>> {
         let result = self.midibox.next()
             .map(|it|
@@ -78,4 +113,18 @@ impl <F> Midibox for MapBeat<F>
         self.curr_beat = (self.curr_beat + 1) % self.max_beat;
         result
     }
+
+    fn reset(&mut self) {
+        self.curr_beat = 0;
+        self.midibox.reset()
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        self.midibox.step_length()
+    }
+
+    /// Assumes that the mapper doesn't change the duration of notes
+    fn period(&self) -> Option<Period> {
+        self.midibox.period().and_then(|p| p.align_steps(self.max_beat as u64))
+    }
 }
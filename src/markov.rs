@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, VecDeque};
+use rand::Rng;
+use rand::rngs::StdRng;
+use crate::Midibox;
+use crate::midi::Midi;
+use crate::rand::session_rng;
+use crate::sequences::Seq;
+
+
+/// Learns how often each value follows each run of `order` values.
+///
+/// Training material is treated as a loop, so that every run of values has a successor.
+#[derive(Debug, Clone)]
+struct Chain<T> where T: Clone + Ord {
+    order: usize,
+    // a sorted map keeps generation reproducible from a seed
+    transitions: BTreeMap<Vec<T>, BTreeMap<T, u32>>,
+}
+
+impl<T> Chain<T> where T: Clone + Ord {
+    fn new(order: usize) -> Self {
+        Chain { order, transitions: BTreeMap::new() }
+    }
+
+    fn train(&mut self, values: &[T]) {
+        let n = values.len();
+        if n == 0 {
+            return;
+        }
+        for i in 0..n {
+            let context: Vec<T> = (0..self.order).map(|j| values[(i + j) % n].clone()).collect();
+            let next = values[(i + self.order) % n].clone();
+            *self.transitions.entry(context).or_default().entry(next).or_insert(0) += 1;
+        }
+    }
+
+    fn start(&self, rng: &mut StdRng) -> Option<Vec<T>> {
+        if self.transitions.is_empty() {
+            return None;
+        }
+        self.transitions.keys().nth(rng.gen_range(0..self.transitions.len())).cloned()
+    }
+
+    /// Picks the value following the context. Counts are raised to the power of
+    /// `1 / temperature`, so low temperatures favor the most common successors and high ones
+    /// even the odds. At a temperature of 0, the most common successor is always picked.
+    fn next(&self, context: &[T], temperature: f64, rng: &mut StdRng) -> Option<T> {
+        let successors = self.transitions.get(context)?;
+        if temperature <= 0.0 {
+            let most = successors.values().max()?;
+            return successors.iter().find(|(_, count)| *count == most).map(|(v, _)| v.clone());
+        }
+        let weights: Vec<f64> = successors.values()
+            .map(|count| (*count as f64).powf(1.0 / temperature))
+            .collect();
+        let mut target = rng.gen::<f64>() * weights.iter().sum::<f64>();
+        for ((value, _), weight) in successors.iter().zip(weights.iter()) {
+            if target < *weight {
+                return Some(value.clone());
+            }
+            target -= weight;
+        }
+        successors.keys().last().cloned()
+    }
+}
+
+// the pitches of a step, with its duration and velocity
+type Step = (Vec<Option<u8>>, u32, u8);
+
+/// Learns a melody from sequences as a Markov chain over their steps, looking back `order` steps,
+/// and generates endless variations on it.
+///
+/// The pitches, duration and velocity of a step are learned together, so every turn the
+/// generated melody takes, along with its rhythm and dynamics, is one found in the sequences.
+#[derive(Debug, Clone)]
+pub struct Markov {
+    chain: Chain<Step>,
+    temperature: f64,
+}
+
+impl Markov {
+    pub fn new(order: usize) -> Self {
+        Markov { chain: Chain::new(order.max(1)), temperature: 1.0 }
+    }
+
+    /// Learns from the steps of a sequence
+    pub fn train(mut self, seq: &Seq) -> Self {
+        self.chain.train(&seq.get_chords().iter()
+            .map(|c| (
+                c.notes.iter().map(|n| n.u8_maybe()).filter(|v| v.is_some()).collect(),
+                c.notes.iter().map(|n| n.duration).max().unwrap_or(0),
+                c.notes.iter().map(|n| n.velocity).max().unwrap_or(0),
+            ))
+            .collect::<Vec<Step>>());
+        self
+    }
+
+    /// How adventurous the generated melody is. At 1 the melody follows the learned odds, at 0
+    /// it always takes the most common turn, and above 1 it takes rare turns more often.
+    pub fn temperature(mut self, temperature: f64) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn generate(&self) -> Box<dyn Midibox> {
+        self.generate_with_rng(session_rng())
+    }
+
+    pub fn generate_with_rng(&self, rng: StdRng) -> Box<dyn Midibox> {
+        Box::new(MarkovMelody { markov: self.clone(), rng, history: VecDeque::new() })
+    }
+}
+
+pub struct MarkovMelody {
+    markov: Markov,
+    rng: StdRng,
+    // the most recently generated steps
+    history: VecDeque<Step>,
+}
+
+impl MarkovMelody {
+    /// Generates the step following the history, starting over from a random context when the
+    /// history is empty or leads nowhere
+    fn advance(&mut self) -> Option<Step> {
+        let (chain, temperature) = (&self.markov.chain, self.markov.temperature);
+        let context: Vec<Step> = self.history.iter().cloned().collect();
+        let next = match chain.next(&context, temperature, &mut self.rng) {
+            Some(next) => next,
+            None => {
+                self.history = chain.start(&mut self.rng)?.into();
+                let context: Vec<Step> = self.history.iter().cloned().collect();
+                chain.next(&context, temperature, &mut self.rng)?
+            }
+        };
+        self.history.push_back(next.clone());
+        self.history.pop_front();
+        Some(next)
+    }
+}
+
+impl Midibox for MarkovMelody {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        let (pitches, duration, velocity) = self.advance()?;
+        if pitches.is_empty() {
+            return Some(vec![Midi::rest().set_duration(duration)]);
+        }
+        Some(pitches.into_iter()
+            .map(|p| Midi::from_option(p).set_duration(duration).set_velocity(velocity))
+            .collect())
+    }
+
+    fn reset(&mut self) {
+        self.history.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use crate::markov::Markov;
+    use crate::midi::Midi;
+    use crate::sequences::Seq;
+    use crate::tone::Tone;
+
+    #[test]
+    fn learned_melody() {
+        let seq = Seq::new(vec![
+            Tone::C.oct(4) * 2,
+            Tone::E.oct(4),
+            Tone::G.oct(4),
+            Tone::E.oct(4) * 2,
+        ]);
+        // with a single loop to learn from and no adventure, the melody plays the loop back
+        let markov = Markov::new(2).train(&seq).temperature(0.0);
+        let mut melody = markov.generate_with_rng(StdRng::seed_from_u64(7));
+        let first: Vec<(Tone, u32)> = (0..4)
+            .map(|_| melody.next().unwrap()[0])
+            .map(|n| (n.tone, n.duration))
+            .collect();
+        let second: Vec<(Tone, u32)> = (0..4)
+            .map(|_| melody.next().unwrap()[0])
+            .map(|n| (n.tone, n.duration))
+            .collect();
+        assert_eq!(first, second);
+
+        let mut other = markov.generate_with_rng(StdRng::seed_from_u64(7));
+        assert_eq!(other.next().unwrap()[0].tone, first[0].0);
+    }
+
+    #[test]
+    fn learned_transitions() {
+        let seq = Seq::new(vec![
+            Tone::C.oct(4) * 2,
+            Tone::E.oct(4),
+            Tone::C.oct(4).set_velocity(80),
+            Tone::G.oct(4) * 3,
+            Tone::E.oct(4).set_velocity(80) * 2,
+            Tone::D.oct(4),
+        ]);
+        let step = |notes: Vec<Midi>| (notes[0].u8_maybe(), notes[0].duration, notes[0].velocity);
+        let trained: Vec<_> = seq.get_chords().iter().map(|c| step(c.notes.clone())).collect();
+
+        let mut melody = Markov::new(1).train(&seq).generate_with_rng(StdRng::seed_from_u64(3));
+        let generated: Vec<_> = (0..50).map(|_| step(melody.next().unwrap())).collect();
+        for pair in generated.windows(2) {
+            let learned = (0..trained.len())
+                .any(|i| trained[i] == pair[0] && trained[(i + 1) % trained.len()] == pair[1]);
+            assert!(learned, "{:?} never follows {:?}", pair[1], pair[0]);
+        }
+    }
+}
@@ -7,6 +7,7 @@ use crate::tone::Tone;
 const DEFAULT_OCT: u8 = 4;
 const DEFAULT_VELOCITY: u8 = 100;
 const DEFAULT_DURATION: u32 = 1;
+const DEFAULT_OFFSET: f32 = 0.0;
 
 pub const NOTE_ON_MSG: u8 = 0x90;
 pub const NOTE_OFF_MSG: u8 = 0x80;
@@ -17,6 +18,9 @@ pub struct Midi {
     pub oct: u8,
     pub velocity: u8,
     pub duration: u32,
+    /// How many ticks the note is delayed by, which may include a fraction of a tick. The
+    /// release of the note is delayed as much.
+    pub offset: f32,
 }
 
 impl Midi {
@@ -26,6 +30,7 @@ impl Midi {
             oct: DEFAULT_OCT,
             velocity: DEFAULT_VELOCITY,
             duration: DEFAULT_DURATION,
+            offset: DEFAULT_OFFSET,
         }
     }
 
@@ -41,7 +46,13 @@ impl Midi {
     }
 
     pub fn from_tone(tone: Tone, oct: u8) -> Midi {
-        Midi { tone, oct, velocity: DEFAULT_VELOCITY, duration: DEFAULT_DURATION }
+        Midi {
+            tone,
+            oct,
+            velocity: DEFAULT_VELOCITY,
+            duration: DEFAULT_DURATION,
+            offset: DEFAULT_OFFSET,
+        }
     }
 
     pub fn from(val: u8) -> Midi {
@@ -57,11 +68,17 @@ impl Midi {
     }
 
     pub fn set_velocity(&self, velocity: u8) -> Self {
-        Midi { tone: self.tone, oct: self.oct, velocity, duration: self.duration }
+        Midi { velocity, ..*self }
     }
 
     pub fn set_duration(&self, duration: u32) -> Self {
-        Midi { tone: self.tone, oct: self.oct, velocity: self.velocity, duration }
+        Midi { duration, ..*self }
+    }
+
+    /// Delays the note by `offset` ticks. Notes can't be played before they're produced, so
+    /// negative offsets count as no offset.
+    pub fn set_offset(&self, offset: f32) -> Self {
+        Midi { offset: offset.max(0.0), ..*self }
     }
 
     pub fn set_pitch_u8(&self, val: Option<u8>) -> Self {
@@ -72,15 +89,18 @@ impl Midi {
     }
 
     pub fn set_pitch(&self, tone: Tone, oct: u8) -> Self {
-        Midi { tone, oct, velocity: self.velocity, duration: self.duration }
+        Midi { tone, oct, ..*self }
     }
 
     pub fn transpose_up(&self, interval: Interval) -> Self {
         self.set_pitch_u8(self.u8_maybe().map(|v| v + interval.steps()))
     }
 
+    /// Notes transposed below C0, the lowest note that can be represented, become rests
     pub fn transpose_down(&self, interval: Interval) -> Self {
-        self.set_pitch_u8(self.u8_maybe().map(|v| v - interval.steps()))
+        self.set_pitch_u8(self.u8_maybe()
+            .and_then(|v| v.checked_sub(interval.steps()))
+            .filter(|v| *v >= 12))
     }
 }
 
@@ -115,6 +135,7 @@ pub trait MutMidi: Sized {
     fn total_duration(&self) -> u32;
     fn duration(self, duration: u32) -> Self;
     fn velocity(self, velocity: u8) -> Self;
+    fn offset(self, offset: f32) -> Self;
     fn pitch(self, tone: Tone, oct: u8) -> Self;
     fn scale_duration(self, factor: u32) -> Self;
     fn transpose_up(self, interval: &Interval) -> Self;
@@ -142,6 +163,10 @@ pub trait ToMidi: ToChord {
         self.midi().set_duration(duration)
     }
 
+    fn set_offset(&self, offset: f32) -> Midi {
+        self.midi().set_offset(offset)
+    }
+
     fn set_pitch_u8(&self, val: Option<u8>) -> Midi {
         self.midi().set_pitch_u8(val)
     }
@@ -180,6 +205,10 @@ impl ToMidi for Midi {
         self.set_duration(duration)
     }
 
+    fn set_offset(&self, offset: f32) -> Midi {
+        self.set_offset(offset)
+    }
+
     fn set_pitch_u8(&self, val: Option<u8>) -> Midi {
         self.set_pitch_u8(val)
     }
@@ -0,0 +1,413 @@
+use std::cell::RefCell;
+use rand::Rng;
+use rand::rngs::StdRng;
+use crate::{map_chords, Midibox, step_duration};
+use crate::midi::{Midi, MutMidi};
+use crate::period::{gcd, lcm, Period};
+use crate::rand::{aperiodic, session_rng};
+use crate::tone::Tone;
+
+// Higher-order transforms over midiboxes, after TidalCycles.
+//
+// Cycle-based transforms take the length of a cycle in ticks, along with a function building
+// the pattern they transform: the transformed and untransformed patterns play side by side, so
+// each needs its own instance.
+
+/// Applies `f` to the pattern on every `n`th cycle, starting with the first.
+pub fn every<P, F>(n: u64, cycle: u32, pattern: P, f: F) -> Box<dyn Midibox>
+    where P: Fn() -> Box<dyn Midibox>, F: FnOnce(Box<dyn Midibox>) -> Box<dyn Midibox>
+{
+    Cycles::wrap(pattern(), f(pattern()), cycle, Some(n.max(1)), move |i| n > 0 && i % n == 0)
+}
+
+/// Applies `f` to the pattern on the cycles where the cycle number modulo `a` is at least `b`.
+pub fn whenmod<P, F>(a: u64, b: u64, cycle: u32, pattern: P, f: F) -> Box<dyn Midibox>
+    where P: Fn() -> Box<dyn Midibox>, F: FnOnce(Box<dyn Midibox>) -> Box<dyn Midibox>
+{
+    Cycles::wrap(pattern(), f(pattern()), cycle, Some(a.max(1)), move |i| a > 0 && i % a >= b)
+}
+
+/// Applies `f` to each cycle of the pattern with probability `p`.
+pub fn sometimes<P, F>(p: f64, cycle: u32, pattern: P, f: F) -> Box<dyn Midibox>
+    where P: Fn() -> Box<dyn Midibox>, F: FnOnce(Box<dyn Midibox>) -> Box<dyn Midibox>
+{
+    sometimes_with_rng(p, cycle, pattern, f, session_rng())
+}
+
+pub fn sometimes_with_rng<P, F>(
+    p: f64,
+    cycle: u32,
+    pattern: P,
+    f: F,
+    rng: StdRng
+) -> Box<dyn Midibox>
+    where P: Fn() -> Box<dyn Midibox>, F: FnOnce(Box<dyn Midibox>) -> Box<dyn Midibox>
+{
+    let rng = RefCell::new(rng);
+    Cycles::wrap(pattern(), f(pattern()), cycle, None, move |_| {
+        rng.borrow_mut().gen_bool(p.clamp(0.0, 1.0))
+    })
+}
+
+/// Plays the pattern `factor` times faster.
+pub fn fast(midibox: Box<dyn Midibox>, factor: u32) -> Box<dyn Midibox> {
+    Stretch::wrap(midibox, 1, factor)
+}
+
+/// Plays the pattern `factor` times slower.
+pub fn slow(midibox: Box<dyn Midibox>, factor: u32) -> Box<dyn Midibox> {
+    Stretch::wrap(midibox, factor, 1)
+}
+
+/// Starts the pattern `ticks` ticks in, at the first step starting at or after that point.
+pub fn rotate(midibox: Box<dyn Midibox>, ticks: u64) -> Box<dyn Midibox> {
+    Rotate::wrap(midibox, ticks)
+}
+
+/// Plays each cycle of the pattern forwards, then backwards.
+pub fn palindrome(midibox: Box<dyn Midibox>, cycle: u32) -> Box<dyn Midibox> {
+    Palindrome::wrap(midibox, cycle)
+}
+
+/// Silences half of the steps of the pattern at random.
+pub fn degrade(midibox: Box<dyn Midibox>) -> Box<dyn Midibox> {
+    degrade_by(midibox, 0.5)
+}
+
+/// Silences each step of the pattern with probability `p`. Unlike `random_dropout`, which drops
+/// individual notes, a step is silenced as a whole.
+pub fn degrade_by(midibox: Box<dyn Midibox>, p: f64) -> Box<dyn Midibox> {
+    degrade_by_with_rng(midibox, p, session_rng())
+}
+
+pub fn degrade_by_with_rng(midibox: Box<dyn Midibox>, p: f64, rng: StdRng) -> Box<dyn Midibox> {
+    let rng = RefCell::new(rng);
+    aperiodic(map_chords(midibox, move |chord| {
+        if rng.borrow_mut().gen_bool(p.clamp(0.0, 1.0)) {
+            chord.pitch(Tone::Rest, 4)
+        } else {
+            chord
+        }
+    }))
+}
+
+/// Switches between two midiboxes at cycle boundaries. `choose` is called with the number of
+/// each cycle and returns whether the transformed midibox plays it.
+///
+/// A midibox that takes over is positioned where it would be had it been playing from the
+/// start. Steps are never cut short, so the switch happens at the first step starting at or
+/// after the cycle boundary.
+///
+/// `repeat` is the number of cycles after which `choose` returns the same values again, if it
+/// does. The midibox only has a period if it's given, and if the cycles line up with the steps of
+/// both midiboxes.
+pub struct Cycles<F> where F: FnMut(u64) -> bool {
+    plain: Box<dyn Midibox>,
+    transformed: Box<dyn Midibox>,
+    cycle: u32,
+    repeat: Option<u64>,
+    choose: F,
+    // ticks played since the start
+    time: u64,
+    // the cycle in which the last step started
+    curr_cycle: Option<u64>,
+    use_transformed: bool,
+    step_length: u32,
+}
+
+impl<F> Cycles<F> where F: FnMut(u64) -> bool + Send + 'static {
+    pub fn wrap(
+        plain: Box<dyn Midibox>,
+        transformed: Box<dyn Midibox>,
+        cycle: u32,
+        repeat: Option<u64>,
+        choose: F
+    ) -> Box<dyn Midibox> {
+        Box::new(Cycles {
+            plain,
+            transformed,
+            cycle: cycle.max(1),
+            repeat: repeat.map(|n| n.max(1)),
+            choose,
+            time: 0,
+            curr_cycle: None,
+            use_transformed: false,
+            step_length: 1,
+        })
+    }
+}
+
+impl<F> Midibox for Cycles<F> where F: FnMut(u64) -> bool + Send {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        let cycle = self.time / self.cycle as u64;
+        if self.curr_cycle != Some(cycle) {
+            let use_transformed = (self.choose)(cycle);
+            if self.curr_cycle.is_none() || use_transformed != self.use_transformed {
+                let midibox = if use_transformed { &mut self.transformed } else { &mut self.plain };
+                let position = match midibox.period() {
+                    Some(period) => self.time % period.ticks,
+                    None => self.time,
+                };
+                midibox.seek(position);
+            }
+            self.curr_cycle = Some(cycle);
+            self.use_transformed = use_transformed;
+        }
+
+        let midibox = if self.use_transformed { &mut self.transformed } else { &mut self.plain };
+        let notes = midibox.next();
+        self.step_length = notes.as_ref().map(|it| step_duration(midibox.as_ref(), it)).unwrap_or(1);
+        self.time += self.step_length as u64;
+        notes
+    }
+
+    fn reset(&mut self) {
+        self.time = 0;
+        self.curr_cycle = None;
+        self.use_transformed = false;
+        self.step_length = 1;
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        Some(self.step_length)
+    }
+
+    fn period(&self) -> Option<Period> {
+        let repeat = self.repeat?;
+        let (plain, transformed) = (self.plain.period()?, self.transformed.period()?);
+        let grid = lcm(plain.grid, transformed.grid);
+        let misaligned = self.cycle as u64 % grid;
+        if misaligned != 0 {
+            // switches are delayed to the next step, which throws the midiboxes out of phase
+            return None;
+        }
+        let ticks = lcm(self.cycle as u64 * repeat, lcm(plain.ticks, transformed.ticks));
+        Some(Period::of_ticks(ticks, grid))
+    }
+}
+
+/// Scales the timing of a midibox by `numerator / denominator`.
+///
+/// Step boundaries are rounded down to whole ticks. Steps that would end up shorter than a tick
+/// are merged into the following step, so their notes play together.
+pub struct Stretch {
+    midibox: Box<dyn Midibox>,
+    numerator: u32,
+    denominator: u32,
+    // ticks played by the wrapped midibox
+    inner_time: u64,
+    step_length: u32,
+}
+
+impl Stretch {
+    pub fn wrap(midibox: Box<dyn Midibox>, numerator: u32, denominator: u32) -> Box<dyn Midibox> {
+        Box::new(Stretch {
+            midibox,
+            numerator: numerator.max(1),
+            denominator: denominator.max(1),
+            inner_time: 0,
+            step_length: 1,
+        })
+    }
+
+    fn scale(&self, ticks: u64) -> u64 {
+        ticks * self.numerator as u64 / self.denominator as u64
+    }
+}
+
+impl Midibox for Stretch {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        let start = self.scale(self.inner_time);
+        let mut notes: Vec<Midi> = Vec::new();
+        while self.scale(self.inner_time) <= start {
+            let step = self.midibox.next()?;
+            self.inner_time += step_duration(self.midibox.as_ref(), &step) as u64;
+            notes.extend(step.into_iter().map(|n| if n.duration == 0 {
+                n
+            } else {
+                n.set_duration((self.scale(n.duration as u64) as u32).max(1))
+            }));
+        }
+        self.step_length = (self.scale(self.inner_time) - start) as u32;
+        Some(notes)
+    }
+
+    fn reset(&mut self) {
+        self.inner_time = 0;
+        self.step_length = 1;
+        self.midibox.reset()
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        Some(self.step_length)
+    }
+
+    fn period(&self) -> Option<Period> {
+        let inner = self.midibox.period()?;
+        let scaled = inner.ticks * self.numerator as u64;
+        let period = inner.repeat(self.denominator as u64 / gcd(scaled, self.denominator as u64));
+        let ticks = self.scale(period.ticks);
+        let misaligned = (inner.grid * self.numerator as u64) % self.denominator as u64;
+        let grid = if misaligned == 0 { self.scale(inner.grid) } else { ticks };
+        Some(Period::of_ticks(ticks, grid))
+    }
+}
+
+pub struct Rotate {
+    midibox: Box<dyn Midibox>,
+    ticks: u64,
+}
+
+impl Rotate {
+    pub fn wrap(mut midibox: Box<dyn Midibox>, ticks: u64) -> Box<dyn Midibox> {
+        midibox.seek(ticks);
+        Box::new(Rotate { midibox, ticks })
+    }
+}
+
+impl Midibox for Rotate {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        self.midibox.next()
+    }
+
+    fn reset(&mut self) {
+        self.midibox.seek(self.ticks)
+    }
+
+    fn seek(&mut self, tick: u64) {
+        self.midibox.seek(self.ticks + tick)
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        self.midibox.step_length()
+    }
+
+    fn period(&self) -> Option<Period> {
+        let inner = self.midibox.period()?;
+        let misaligned = self.ticks % inner.grid.max(1);
+        if misaligned != 0 {
+            // the rotation is rounded up to the next step, so only whole periods line up
+            return Some(Period { grid: inner.ticks, ..inner });
+        }
+        Some(inner)
+    }
+}
+
+pub struct Palindrome {
+    midibox: Box<dyn Midibox>,
+    cycle: u32,
+    // the steps of the cycle being played forwards, with their lengths
+    played: Vec<(Vec<Midi>, u32)>,
+    played_ticks: u64,
+    // the steps left to play backwards
+    reversed: Vec<(Vec<Midi>, u32)>,
+    step_length: u32,
+}
+
+impl Palindrome {
+    pub fn wrap(midibox: Box<dyn Midibox>, cycle: u32) -> Box<dyn Midibox> {
+        Box::new(Palindrome {
+            midibox,
+            cycle: cycle.max(1),
+            played: Vec::new(),
+            played_ticks: 0,
+            reversed: Vec::new(),
+            step_length: 1,
+        })
+    }
+}
+
+impl Midibox for Palindrome {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        if let Some((notes, step)) = self.reversed.pop() {
+            self.step_length = step;
+            return Some(notes);
+        }
+
+        let notes = self.midibox.next()?;
+        let step = step_duration(self.midibox.as_ref(), &notes);
+        self.played.push((notes.clone(), step));
+        self.played_ticks += step as u64;
+        if self.played_ticks >= self.cycle as u64 {
+            // popping from the end of the played steps plays them backwards
+            self.reversed = std::mem::take(&mut self.played);
+            self.played_ticks = 0;
+        }
+        self.step_length = step;
+        Some(notes)
+    }
+
+    fn reset(&mut self) {
+        self.played.clear();
+        self.played_ticks = 0;
+        self.reversed.clear();
+        self.step_length = 1;
+        self.midibox.reset()
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        Some(self.step_length)
+    }
+
+    fn period(&self) -> Option<Period> {
+        let inner = self.midibox.period()?;
+        let misaligned = self.cycle as u64 % inner.grid;
+        if misaligned != 0 {
+            return None;
+        }
+        let ticks = 2 * lcm(inner.ticks, self.cycle as u64);
+        Some(Period::of_ticks(ticks, inner.grid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Midibox;
+    use crate::pattern::{every, fast, palindrome, slow, sometimes, whenmod};
+    use crate::period::Period;
+    use crate::sequences::Seq;
+    use crate::tone::Tone;
+
+    fn tones(midibox: &mut Box<dyn Midibox>, steps: usize) -> Vec<(Tone, u32)> {
+        (0..steps)
+            .map(|_| midibox.next().unwrap())
+            .map(|notes| (notes[0].tone, notes[0].duration))
+            .collect()
+    }
+
+    #[test]
+    fn every_other_cycle() {
+        let seq = Seq::new(vec![Tone::C.oct(4) * 2, Tone::D.oct(4) * 2]);
+        let mut pattern = every(2, 4, || seq.midibox(), |p| fast(p, 2));
+        assert_eq!(tones(&mut pattern, 8), vec![
+            (Tone::C, 1), (Tone::D, 1), (Tone::C, 1), (Tone::D, 1),
+            (Tone::C, 2), (Tone::D, 2),
+            (Tone::C, 1), (Tone::D, 1),
+        ]);
+        assert_eq!(pattern.period(), Some(Period::of_ticks(8, 2)));
+        let pattern = whenmod(3, 2, 4, || seq.midibox(), |p| fast(p, 2));
+        assert_eq!(pattern.period(), Some(Period::of_ticks(12, 2)));
+        assert_eq!(every(2, 3, || seq.midibox(), |p| fast(p, 2)).period(), None);
+        assert_eq!(sometimes(0.5, 4, || seq.midibox(), |p| fast(p, 2)).period(), None);
+    }
+
+    #[test]
+    fn stretch() {
+        let seq = Seq::new(vec![Tone::C.oct(4), Tone::D.oct(4), Tone::E.oct(4) * 2]);
+        let mut faster = fast(seq.midibox(), 2);
+        assert_eq!(tones(&mut faster, 2), vec![(Tone::C, 1), (Tone::E, 1)]);
+        assert_eq!(faster.period(), Some(Period::of_ticks(2, 2)));
+        let mut slower = slow(seq.midibox(), 3);
+        assert_eq!(tones(&mut slower, 3), vec![(Tone::C, 3), (Tone::D, 3), (Tone::E, 6)]);
+    }
+
+    #[test]
+    fn palindrome_cycles() {
+        let seq = Seq::new(vec![Tone::C.oct(4), Tone::D.oct(4), Tone::E.oct(4), Tone::F.oct(4)]);
+        let mut pattern = palindrome(seq.midibox(), 2);
+        let played: Vec<Tone> = tones(&mut pattern, 8).into_iter().map(|(t, _)| t).collect();
+        assert_eq!(played, vec![
+            Tone::C, Tone::D, Tone::D, Tone::C, Tone::E, Tone::F, Tone::F, Tone::E,
+        ]);
+    }
+}
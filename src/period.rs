@@ -0,0 +1,124 @@
+use crate::Midibox;
+
+
+/// Describes when a midibox repeats itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Period {
+    /// The number of ticks before the midibox repeats
+    pub ticks: u64,
+    /// The number of calls to `next` before the midibox repeats, if known
+    pub steps: Option<u64>,
+    /// Every multiple of this many ticks falls on a step boundary
+    pub grid: u64,
+}
+
+impl Period {
+    pub fn new(ticks: u64, steps: u64, grid: u64) -> Self {
+        Period { ticks, steps: Some(steps), grid }
+    }
+
+    /// A period for midiboxes that can't tell how many steps they take to repeat
+    pub fn of_ticks(ticks: u64, grid: u64) -> Self {
+        Period { ticks, steps: None, grid }
+    }
+
+    /// Computes the period of steps with the given durations played in a loop. As in the player,
+    /// a step without any notes takes a single tick.
+    pub fn of_steps(durations: &[u64]) -> Option<Period> {
+        let durations: Vec<u64> = durations.iter().map(|d| (*d).max(1)).collect();
+        let ticks: u64 = durations.iter().sum();
+        if ticks == 0 {
+            return None;
+        }
+        let grid = if durations.iter().all(|d| *d == durations[0]) { durations[0] } else { ticks };
+        Some(Period::new(ticks, durations.len() as u64, grid))
+    }
+
+    /// The period of playing this period `times` times over
+    pub fn repeat(self, times: u64) -> Self {
+        Period {
+            ticks: self.ticks * times.max(1),
+            steps: self.steps.map(|steps| steps * times.max(1)),
+            grid: self.grid,
+        }
+    }
+
+    /// Extends the period until it spans a multiple of `steps` steps, if the number of steps in
+    /// the period is known
+    pub fn align_steps(self, steps: u64) -> Option<Self> {
+        let own = self.steps?;
+        Some(self.repeat(lcm(own, steps) / own.max(1)))
+    }
+
+    /// Extends the period until it spans a multiple of `ticks` ticks
+    pub fn align_ticks(self, ticks: u64) -> Self {
+        self.repeat(lcm(self.ticks, ticks) / self.ticks.max(1))
+    }
+}
+
+pub fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+pub fn lcm(a: u64, b: u64) -> u64 {
+    if a == 0 || b == 0 {
+        return a.max(b);
+    }
+    a / gcd(a, b) * b
+}
+
+/// Computes the number of ticks after which all of the channels repeat together, or `None` if
+/// any of them doesn't repeat.
+pub fn combined_period(channels: &[Box<dyn Midibox>]) -> Option<u64> {
+    if channels.is_empty() {
+        return None;
+    }
+    channels.iter().try_fold(1, |ticks, channel| channel.period().map(|p| lcm(ticks, p.ticks)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arp::Arpeggio;
+    use crate::map_beat;
+    use crate::chord::Chord;
+    use crate::dropout::{Dropout, random_dropout};
+    use crate::period::{combined_period, Period};
+    use crate::sequences::{Condition, Seq};
+    use crate::tone::Tone;
+
+    #[test]
+    fn seq() {
+        let seq = Seq::new(vec![Tone::C.oct(4) * 2, Tone::D.oct(4) * 3, Tone::Rest * 0]);
+        assert_eq!(seq.total_duration(), 6);
+        assert_eq!(seq.period(), Some(Period::new(6, 3, 6)));
+        assert_eq!(
+            seq.clone().condition(0, Condition::Every(1, 4)).period(),
+            Some(Period::new(24, 12, 6))
+        );
+        assert_eq!(seq.condition(0, Condition::Probability(0.5)).period(), None);
+    }
+
+    #[test]
+    fn wrappers() {
+        let seq = Seq::new(vec![Tone::C.oct(4) * 2, Tone::D.oct(4) * 2, Tone::E.oct(4) * 2]);
+        assert_eq!(
+            map_beat(seq.midibox(), 2, |m, _| m).period(),
+            Some(Period::new(12, 6, 2))
+        );
+        assert_eq!(
+            Dropout::wrap(seq.midibox(), 4, true).period(),
+            Some(Period::new(24, 12, 2))
+        );
+        assert_eq!(Dropout::wrap(seq.midibox(), 3, true).period(), None);
+        assert_eq!(random_dropout(seq.midibox(), 0.5).period(), None);
+
+        let triad = Chord::new(vec![Tone::C.oct(4), Tone::E.oct(4), Tone::G.oct(4)]);
+        let arp = Arpeggio::ascend(Seq::chords(vec![triad]).duration(10), 4);
+        assert_eq!(arp.period(), Some(Period::new(12, 3, 4)));
+
+        assert_eq!(
+            combined_period(&[seq.midibox(), Seq::new(vec![Tone::C.oct(4) * 4]).midibox()]),
+            Some(12)
+        );
+    }
+}
@@ -3,13 +3,15 @@ use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
+use std::time::Duration;
 use crossbeam::atomic::AtomicCell;
 
 use ctrlc;
 use midir::{MidiOutput, MidiOutputConnection};
-use crate::Midibox;
+use crate::{Midibox, step_duration};
 use crate::meter::Meter;
 use crate::midi::{Midi, NOTE_OFF_MSG, NOTE_ON_MSG};
+use crate::rand::session_seed;
 use crate::router::{Router, StaticRouter};
 
 
@@ -21,6 +23,8 @@ pub struct Player {
     /// A map from a sounding note's ID to the note, decorated with metadata about how the note was
     /// generated.
     playing_notes: HashMap<u64, PlayingNote>,
+    /// A map from a channel's ID to the tick at which the channel's current step ends.
+    next_poll: HashMap<usize, u64>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -36,6 +40,7 @@ impl Player {
             tick_id: 0,
             note_id: 0,
             playing_notes: HashMap::new(),
+            next_poll: HashMap::new(),
         }
     }
 
@@ -47,6 +52,44 @@ impl Player {
         self.tick_id
     }
 
+    /// Increment and return the tick_id, sending each of the given notes with its MIDI status at
+    /// its offset into the tick while waiting for the tick's duration. Notes released at the same
+    /// offset that others start at are released first, so that repeated notes are retriggered.
+    ///
+    /// `send` is given each note along with how long from now it's due. With
+    /// [SendTiming::Timestamped], all of the notes are handed over at the start of the tick for
+    /// the output to schedule. With [SendTiming::Sleep], the tick's sleep is split around the
+    /// notes in order of their offset, and each note is handed over once it's due.
+    pub fn do_tick_with_offsets<F>(
+        &mut self,
+        meter: &mut dyn Meter,
+        mut notes: Vec<(PlayingNote, u8)>,
+        timing: SendTiming,
+        mut send: F
+    ) -> u64 where F: FnMut(&PlayingNote, u8, Duration) {
+        let tick_duration = meter.tick_duration();
+        notes.sort_by(|(a, a_status), (b, b_status)| {
+            a.note.offset.total_cmp(&b.note.offset).then(a_status.cmp(b_status))
+        });
+        let mut elapsed = Duration::ZERO;
+        for (note, midi_status) in notes.iter() {
+            let send_at = tick_duration.mul_f32(note.note.offset.clamp(0.0, 1.0));
+            match timing {
+                SendTiming::Timestamped => send(note, *midi_status, send_at),
+                SendTiming::Sleep => {
+                    if send_at > elapsed {
+                        sleep(send_at - elapsed);
+                        elapsed = send_at;
+                    }
+                    send(note, *midi_status, Duration::ZERO);
+                }
+            }
+        }
+        sleep(tick_duration.saturating_sub(elapsed));
+        self.tick_id += 1;
+        self.tick_id
+    }
+
     /// Gets the current time in ticks since start
     pub fn time(&self) -> u64 {
         self.tick_id
@@ -54,11 +97,13 @@ impl Player {
 
     /// Determines whether we need to poll the channel for new notes in the sequence
     /// Each channel may send a set of notes to the player -- but cannot send any more notes until
-    /// those are done playing. So check that there are no active notes for the channel.
+    /// its step is over. Unless the channel says otherwise, a step lasts until its notes are done
+    /// playing.
     fn should_poll_channel(&self, channel_id: usize) -> bool {
-        self.playing_notes.values()
-            .filter(|v| v.channel_id == channel_id)
-            .count() == 0
+        match self.next_poll.get(&channel_id) {
+            Some(tick) => *tick <= self.tick_id,
+            None => true,
+        }
     }
 
     /// TODO: Testing for multiple notes of different durations.
@@ -75,6 +120,8 @@ impl Player {
             match channel.next() {
                 Some(notes) => {
                     debug!("Channel {} sent notes {:?}", channel_id, notes);
+                    let step = step_duration(channel.as_ref(), &notes);
+                    self.next_poll.insert(channel_id, self.tick_id + step as u64);
                     for note in notes {
                         self.note_id += 1;
                         let note_id = self.note_id;
@@ -82,16 +129,19 @@ impl Player {
                             continue; // ignore zero-duration notes
                         }
                         // track the note we're about to play so that we can stop it after the
-                        // number of ticks equaling the note's duration have elapsed.
+                        // number of ticks equaling the note's duration have elapsed. Whole ticks
+                        // of the note's offset move the tick that it starts on.
+                        let delay = note.offset.floor();
                         self.playing_notes.insert(note_id, PlayingNote {
                             channel_id,
-                            start_tick_id: self.tick_id,
-                            note,
+                            start_tick_id: self.tick_id + delay as u64,
+                            note: note.set_offset(note.offset - delay),
                         });
                     }
                 }
                 None => {
                     error!("No input from channel {}", channel_id);
+                    self.next_poll.insert(channel_id, self.tick_id + 1);
                 }
             }
         }
@@ -105,6 +155,36 @@ impl Player {
         notes
     }
 
+    /// Applies changes to the channels, returning the sounding notes of the channels removed or
+    /// replaced so that they can be released. New channels are polled on the current tick.
+    pub fn swap_channels(
+        &mut self,
+        channels: &mut Vec<Box<dyn Midibox>>,
+        swaps: Vec<Swap>
+    ) -> Vec<PlayingNote> {
+        let mut released: Vec<PlayingNote> = Vec::new();
+        for swap in swaps {
+            let (channel_id, midibox) = match swap {
+                Swap::Add(midibox) => {
+                    channels.push(midibox);
+                    self.next_poll.remove(&(channels.len() - 1));
+                    continue;
+                }
+                Swap::Remove(channel_id) => (channel_id, Box::new(Silence) as Box<dyn Midibox>),
+                Swap::Replace(channel_id, midibox) => (channel_id, midibox),
+            };
+            match channels.get_mut(channel_id) {
+                Some(channel) => {
+                    *channel = midibox;
+                    self.next_poll.remove(&channel_id);
+                    released.extend(self.clear_notes(|note| note.channel_id == channel_id));
+                }
+                None => error!("No channel to swap! channel_id = {}", channel_id),
+            }
+        }
+        released
+    }
+
     pub fn clear_elapsed_notes(&mut self) -> Vec<PlayingNote> {
         let current_tick = self.tick_id;
         self.clear_notes(|note| {
@@ -137,6 +217,95 @@ impl Default for Player {
     }
 }
 
+/// How the player sends notes at their offsets into a tick, see [Player::do_tick_with_offsets]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendTiming {
+    /// The output schedules each note itself, given how long from now to send it
+    Timestamped,
+    /// The player sleeps until each note is due, for outputs that send notes as soon as they're
+    /// handed over
+    Sleep,
+}
+
+/// A change to the channels of a running player, see [ChannelHandle]
+pub enum Swap {
+    Add(Box<dyn Midibox>),
+    Remove(usize),
+    Replace(usize, Box<dyn Midibox>),
+}
+
+/// Changes the channels of a player while it plays, see [try_run_live].
+///
+/// Changes are held until the start of the next bar so that new channels start in time with the
+/// others. Removed channels are left silent rather than taken out, so that the ids of the other
+/// channels, and the ports they're routed to, don't change. Notes of added channels that the
+/// router sends to a port it doesn't require are dropped, as the port isn't connected.
+#[derive(Clone)]
+pub struct ChannelHandle {
+    ticks_per_bar: u64,
+    pending: Arc<Mutex<PendingSwaps>>,
+}
+
+struct PendingSwaps {
+    // the number of channels the player has, before the swaps are applied
+    channels: usize,
+    swaps: Vec<Swap>,
+}
+
+impl ChannelHandle {
+    /// A handle for a player starting with the given number of channels
+    pub fn new(ticks_per_bar: u64, channels: usize) -> Self {
+        ChannelHandle {
+            ticks_per_bar: ticks_per_bar.max(1),
+            pending: Arc::new(Mutex::new(PendingSwaps { channels, swaps: vec![] })),
+        }
+    }
+
+    /// Adds a channel after the existing ones, returning the id the channel will have
+    pub fn add(&self, midibox: Box<dyn Midibox>) -> usize {
+        let mut pending = self.pending.lock().unwrap();
+        let added = pending.swaps.iter().filter(|swap| matches!(swap, Swap::Add(_))).count();
+        pending.swaps.push(Swap::Add(midibox));
+        pending.channels + added
+    }
+
+    /// Silences a channel, releasing its sounding notes
+    pub fn remove(&self, channel_id: usize) {
+        self.pending.lock().unwrap().swaps.push(Swap::Remove(channel_id))
+    }
+
+    /// Plays a midibox in place of a channel, releasing the channel's sounding notes
+    pub fn replace(&self, channel_id: usize, midibox: Box<dyn Midibox>) {
+        self.pending.lock().unwrap().swaps.push(Swap::Replace(channel_id, midibox))
+    }
+
+    /// The number of channels the player has, before pending changes are applied
+    fn channels(&self) -> usize {
+        self.pending.lock().unwrap().channels
+    }
+
+    /// The changes due at the given tick, in the order they were made
+    fn take_due(&self, tick: u64) -> Vec<Swap> {
+        let misaligned = tick % self.ticks_per_bar;
+        if misaligned != 0 {
+            return vec![];
+        }
+        let mut pending = self.pending.lock().unwrap();
+        let swaps: Vec<Swap> = pending.swaps.drain(..).collect();
+        pending.channels += swaps.iter().filter(|swap| matches!(swap, Swap::Add(_))).count();
+        swaps
+    }
+}
+
+/// Stands in for a removed channel
+struct Silence;
+
+impl Midibox for Silence {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        Some(vec![])
+    }
+}
+
 pub struct PlayerConfig {
     router: Box<dyn Router>
 }
@@ -196,6 +365,72 @@ pub fn try_run_ext(
     channels: &mut Vec<Box<dyn Midibox>>,
     running: &Arc<Mutex<HashMap<String, bool>>>
 ) -> Result<(), Box<dyn Error>> {
+    run(name, player_config, bpm, channels, None, running)
+}
+
+/// Like `try_run_ext`, with channels that can be added, removed and replaced through the handle
+/// while playing
+pub fn try_run_live(
+    name: &str,
+    player_config: PlayerConfig,
+    bpm: &mut dyn Meter,
+    channels: &mut Vec<Box<dyn Midibox>>,
+    handle: &ChannelHandle,
+    running: &Arc<Mutex<HashMap<String, bool>>>
+) -> Result<(), Box<dyn Error>> {
+    run(name, player_config, bpm, channels, Some(handle), running)
+}
+
+fn run(
+    name: &str,
+    player_config: PlayerConfig,
+    bpm: &mut dyn Meter,
+    channels: &mut Vec<Box<dyn Midibox>>,
+    handle: Option<&ChannelHandle>,
+    running: &Arc<Mutex<HashMap<String, bool>>>
+) -> Result<(), Box<dyn Error>> {
+    if let Some(handle) = handle {
+        if handle.channels() != channels.len() {
+            return Err(format!(
+                "Channel handle made for {} channels, playing {}", handle.channels(), channels.len()
+            ).into());
+        }
+    }
+    let mut port_id_to_conn = connect_ports(&player_config)?;
+    let mut player = Player::new();
+
+    info!("Player Starting. Session seed: {}", session_seed());
+    while *running.lock().unwrap().get(name).unwrap() {
+        debug!("Time: {}", player.time());
+        if let Some(handle) = handle {
+            let swaps = handle.take_due(player.time());
+            for note in player.swap_channels(channels, swaps) {
+                route_note(&player_config, &mut port_id_to_conn, &note, NOTE_OFF_MSG)
+            }
+        }
+        // notes are released at the same offset into the tick that they started at, and both
+        // are sent while the player waits for the next tick
+        let mut notes: Vec<(PlayingNote, u8)> = player.clear_elapsed_notes().into_iter()
+            .map(|note| (note, NOTE_OFF_MSG))
+            .collect();
+        notes.extend(player.poll_channels(channels).into_iter().map(|note| (note, NOTE_ON_MSG)));
+        // midir connections send messages as soon as they're handed over: even with
+        // `coremidi_send_timestamped`, messages are stamped with the time they're sent at
+        player.do_tick_with_offsets(bpm, notes, SendTiming::Sleep, |note, midi_status, _| {
+            route_note(&player_config, &mut port_id_to_conn, note, midi_status)
+        });
+    }
+    for note in player.clear_all_notes() {
+        route_note(&player_config, &mut port_id_to_conn, &note, NOTE_OFF_MSG)
+    }
+    info!("Player Exiting.");
+    Ok(())
+}
+
+/// Opens a connection to each of the output ports required by the router, by port index.
+pub(crate) fn connect_ports(
+    router: &dyn Router
+) -> Result<HashMap<usize, MidiOutputConnection>, Box<dyn Error>> {
     let midi_out = MidiOutput::new("Midi Outputs")?;
     let out_ports = midi_out.ports();
 
@@ -203,7 +438,7 @@ pub fn try_run_ext(
         info!("{}: {}", i, midi_out.port_name(p).unwrap());
     }
 
-    let required_ports = player_config.required_ports();
+    let required_ports = router.required_ports();
     let mut port_id_to_conn: HashMap<usize, MidiOutputConnection> =
         HashMap::with_capacity(required_ports.len());
 
@@ -217,28 +452,10 @@ pub fn try_run_ext(
             port_id_to_conn.insert(i, conn);
         }
     }
-
-    let mut player = Player::new();
-
-    info!("Player Starting.");
-    while *running.lock().unwrap().get(name).unwrap() {
-        debug!("Time: {}", player.time());
-        for note in player.poll_channels(channels) {
-            route_note(&player_config, &mut port_id_to_conn, &note, NOTE_ON_MSG)
-        }
-        player.do_tick(bpm);
-        for note in player.clear_elapsed_notes() {
-            route_note(&player_config, &mut port_id_to_conn, &note, NOTE_OFF_MSG)
-        }
-    }
-    for note in player.clear_all_notes() {
-        route_note(&player_config, &mut port_id_to_conn, &note, NOTE_OFF_MSG)
-    }
-    info!("Player Exiting.");
-    Ok(())
+    Ok(port_id_to_conn)
 }
 
-fn route_note(
+pub(crate) fn route_note(
     player_config: &PlayerConfig,
     device_conn: &mut HashMap<usize, MidiOutputConnection>,
     playing: &PlayingNote,
@@ -255,13 +472,158 @@ fn route_note(
                 None => {
                     error!("No port configured for channel! channel_id = {}", playing.channel_id);
                 }
-                Some(port_id) => {
-                    device_conn.get_mut(port_id)
-                        .unwrap_or_else(|| panic!("Could not find connection for port {}", port_id))
-                        .send(&note)
-                        .unwrap_or_else(|err| panic!("Failed to send note to port {}, {}", port_id, err))
+                Some(port_id) => match device_conn.get_mut(port_id) {
+                    None => {
+                        error!("No connection to port {}! channel_id = {}",
+                            port_id, playing.channel_id);
+                    }
+                    Some(conn) => {
+                        conn.send(&note)
+                            .unwrap_or_else(|err| panic!("Failed to send note to port {}, {}", port_id, err))
+                    }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::Midibox;
+    use crate::meter::Meter;
+    use crate::midi::{Midi, NOTE_OFF_MSG, NOTE_ON_MSG};
+    use crate::player::{ChannelHandle, Player, PlayingNote, SendTiming};
+    use crate::sequences::Seq;
+    use crate::tone::Tone;
+
+    /// Lets each of its notes ring over the following step
+    struct Ringing {
+        notes: Vec<Midi>,
+        position: usize,
+    }
+
+    impl Midibox for Ringing {
+        fn next(&mut self) -> Option<Vec<Midi>> {
+            let note = self.notes[self.position % self.notes.len()];
+            self.position += 1;
+            Some(vec![note])
+        }
+
+        fn step_length(&self) -> Option<u32> {
+            Some(2)
+        }
+    }
+
+    #[test]
+    fn polls_when_step_ends() {
+        let mut player = Player::new();
+        let mut channels: Vec<Box<dyn Midibox>> = vec![
+            Box::new(Ringing { notes: vec![Tone::C.oct(4) * 4, Tone::E.oct(4) * 4], position: 0 }),
+            Seq::new(vec![Tone::G.oct(4) * 3, Tone::B.oct(4)]).midibox(),
+        ];
+        let mut started: Vec<(u64, usize, Tone)> = vec![];
+        for _ in 0..5 {
+            for note in player.poll_channels(&mut channels) {
+                started.push((note.start_tick_id, note.channel_id, note.note.tone));
+            }
+            player.tick_id += 1;
+            player.clear_elapsed_notes();
+        }
+        started.sort_by_key(|(tick, channel_id, _)| (*tick, *channel_id));
+        // the ringing channel is polled while its notes still sound, the other once they're done
+        assert_eq!(started, vec![
+            (0, 0, Tone::C),
+            (0, 1, Tone::G),
+            (2, 0, Tone::E),
+            (3, 1, Tone::B),
+            (4, 0, Tone::C),
+            (4, 1, Tone::G),
+        ]);
+    }
+
+    #[test]
+    fn offsets_carry_whole_ticks() {
+        let mut player = Player::new();
+        let mut channels = vec![Seq::new(vec![Tone::C.oct(4).set_offset(1.5) * 2]).midibox()];
+        assert!(player.poll_channels(&mut channels).is_empty());
+        player.tick_id += 1;
+        let started = player.poll_channels(&mut channels);
+        assert_eq!((started[0].start_tick_id, started[0].note.offset), (1, 0.5));
+        player.tick_id += 1;
+        assert!(player.clear_elapsed_notes().is_empty());
+        player.tick_id += 1;
+        // released as far into its last tick as it started into its first
+        let released = player.clear_elapsed_notes();
+        assert_eq!((released[0].note.tone, released[0].note.offset), (Tone::C, 0.5));
+    }
+
+    struct Ticks(Duration);
+
+    impl Meter for Ticks {
+        fn tick_duration(&mut self) -> Duration {
+            self.0
+        }
+    }
+
+    #[test]
+    fn timestamped_sends() {
+        let note = |tone: Tone, offset: f32| PlayingNote {
+            channel_id: 0,
+            start_tick_id: 0,
+            note: tone.oct(4).set_offset(offset),
+        };
+        let notes = vec![
+            (note(Tone::E, 0.5), NOTE_ON_MSG),
+            (note(Tone::C, 0.0), NOTE_ON_MSG),
+            (note(Tone::E, 0.5), NOTE_OFF_MSG),
+        ];
+        let mut player = Player::new();
+        let mut meter = Ticks(Duration::from_millis(4));
+        let mut sent: Vec<(Tone, u8, Duration)> = vec![];
+        player.do_tick_with_offsets(&mut meter, notes.clone(), SendTiming::Timestamped, |n, s, at| {
+            sent.push((n.note.tone, s, at))
+        });
+        assert_eq!(sent, vec![
+            (Tone::C, NOTE_ON_MSG, Duration::ZERO),
+            (Tone::E, NOTE_OFF_MSG, Duration::from_millis(2)),
+            (Tone::E, NOTE_ON_MSG, Duration::from_millis(2)),
+        ]);
+
+        let mut slept: Vec<(Tone, u8, Duration)> = vec![];
+        player.do_tick_with_offsets(&mut meter, notes, SendTiming::Sleep, |n, s, at| {
+            slept.push((n.note.tone, s, at))
+        });
+        assert_eq!(slept.iter().map(|(_, _, at)| *at).max(), Some(Duration::ZERO));
+        assert_eq!(player.time(), 2);
+    }
+
+    #[test]
+    fn swap_on_bar() {
+        let handle = ChannelHandle::new(4, 1);
+        let mut player = Player::new();
+        let mut channels = vec![Seq::new(vec![Tone::C.oct(4) * 8]).midibox()];
+        player.poll_channels(&mut channels);
+
+        handle.replace(0, Seq::new(vec![Tone::E.oct(4) * 8]).midibox());
+        assert_eq!(handle.add(Seq::new(vec![Tone::G.oct(4) * 8]).midibox()), 1);
+        assert!(handle.take_due(2).is_empty());
+        let released = player.swap_channels(&mut channels, handle.take_due(4));
+        assert_eq!(released.iter().map(|n| n.note.tone).collect::<Vec<Tone>>(), vec![Tone::C]);
+
+        let mut started: Vec<(usize, Tone)> = player.poll_channels(&mut channels).iter()
+            .map(|n| (n.channel_id, n.note.tone))
+            .collect();
+        started.sort_by_key(|(channel_id, _)| *channel_id);
+        assert_eq!(started, vec![(0, Tone::E), (1, Tone::G)]);
+
+        handle.remove(0);
+        assert_eq!(handle.add(Seq::new(vec![Tone::A.oct(4) * 8]).midibox()), 2);
+        assert_eq!(handle.add(Seq::new(vec![Tone::B.oct(4) * 8]).midibox()), 3);
+        let released = player.swap_channels(&mut channels, handle.take_due(8));
+        assert_eq!(released.iter().map(|n| n.note.tone).collect::<Vec<Tone>>(), vec![Tone::E]);
+        assert_eq!(channels.len(), 4);
+        assert_eq!(handle.channels(), 4);
+        assert!(channels[0].next().unwrap().is_empty());
+    }
+}
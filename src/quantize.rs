@@ -0,0 +1,64 @@
+use std::sync::Arc;
+use crossbeam::atomic::AtomicCell;
+use crate::{map_notes, Midibox};
+use crate::scale::{Scale, Snap};
+
+
+/// Snaps every note produced by a Midibox to a tone of the scale.
+///
+/// Unlike `Scale::harmonize_up`, which gives up on notes outside of the scale, every note is
+/// kept and moved to the nearest tone of the scale in the direction given by `snap`.
+pub fn quantize(midibox: Box<dyn Midibox>, scale: Scale, snap: Snap) -> Box<dyn Midibox> {
+    map_notes(midibox, move |m| scale.quantize(m, snap))
+}
+
+/// Snaps every note produced by a Midibox to a tone of one of several scales, chosen while
+/// playing through `selected`, an index into `scales`. Notes pass through unchanged while the
+/// index is out of range.
+pub fn quantize_switch(
+    midibox: Box<dyn Midibox>,
+    scales: Vec<Scale>,
+    selected: Arc<AtomicCell<usize>>,
+    snap: Snap
+) -> Box<dyn Midibox> {
+    map_notes(midibox, move |m| match scales.get(selected.load()) {
+        Some(scale) => scale.quantize(m, snap),
+        None => m,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crossbeam::atomic::AtomicCell;
+    use crate::quantize::quantize_switch;
+    use crate::scale::{Scale, Snap};
+    use crate::sequences::Seq;
+    use crate::tone::Tone;
+
+    #[test]
+    fn snap() {
+        let scale = Scale::major(Tone::C);
+        assert_eq!(scale.quantize(Tone::Db.oct(4), Snap::Nearest), Tone::C.oct(4));
+        assert_eq!(scale.quantize(Tone::Db.oct(4), Snap::Up), Tone::D.oct(4));
+        assert_eq!(scale.quantize(Tone::Bb.oct(3), Snap::Up), Tone::B.oct(3));
+        assert_eq!(scale.quantize(Tone::E.oct(4), Snap::Down), Tone::E.oct(4));
+        assert_eq!(scale.quantize(Tone::Rest.oct(4), Snap::Down), Tone::Rest.oct(4));
+
+        // snapping at the bottom of the range
+        let scale = Scale::major(Tone::D);
+        assert_eq!(scale.quantize(Tone::C.oct(0), Snap::Nearest), Tone::Db.oct(0));
+        assert_eq!(scale.quantize(Tone::C.oct(0), Snap::Down), Tone::C.oct(0));
+    }
+
+    #[test]
+    fn switch_scales() {
+        let selected = Arc::new(AtomicCell::new(0));
+        let scales = vec![Scale::major(Tone::C), Scale::major(Tone::D)];
+        let seq = Seq::new(vec![Tone::F.oct(4)]);
+        let mut quantized = quantize_switch(seq.midibox(), scales, selected.clone(), Snap::Up);
+        assert_eq!(quantized.next().unwrap()[0].tone, Tone::F);
+        selected.store(1);
+        assert_eq!(quantized.next().unwrap()[0].tone, Tone::Gb);
+    }
+}
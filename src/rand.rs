@@ -1,16 +1,94 @@
+use std::cell::RefCell;
+use std::env;
+use std::f64::consts::PI;
 use std::ops::Deref;
-use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::info;
 use rand::distributions::uniform::SampleRange;
-use crate::{Map, map_notes, Midibox};
-use rand::Rng;
-use crate::midi::Midi;
+use crate::{Map, map_beat, map_chords, map_notes, Midibox};
+use rand::{Rng, RngCore, SeedableRng};
+use rand::rngs::StdRng;
+use crate::chord::Chord;
+use crate::midi::{Midi, MutMidi};
+use crate::period::Period;
+
+/// Environment variable holding the session seed of a performance to replay
+pub const SEED_ENV_VAR: &str = "MIDIBOX_SEED";
+
+static SESSION_SEED: Mutex<Option<u64>> = Mutex::new(None);
+static SESSION_STREAM: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the seed all stochastic midiboxes draw from. Call before building channels to replay a
+/// performance.
+pub fn set_session_seed(seed: u64) {
+    *SESSION_SEED.lock().unwrap() = Some(seed);
+    SESSION_STREAM.store(0, Ordering::SeqCst);
+    info!("Session seed: {}", seed);
+}
+
+/// Gets the seed of this session, read from `MIDIBOX_SEED` or chosen at random on first use.
+pub fn session_seed() -> u64 {
+    *SESSION_SEED.lock().unwrap().get_or_insert_with(|| {
+        let seed = env::var(SEED_ENV_VAR).ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| rand::thread_rng().gen());
+        info!("Session seed: {} (set {}={} to replay)", seed, SEED_ENV_VAR, seed);
+        seed
+    })
+}
+
+/// Creates a random number generator derived from the session seed.
+///
+/// Each call returns the next of a series of independent generators, so a performance is
+/// reproduced exactly as long as its midiboxes are built in the same order.
+pub fn session_rng() -> StdRng {
+    let stream = SESSION_STREAM.fetch_add(1, Ordering::SeqCst);
+    StdRng::seed_from_u64(session_seed() ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+}
+
+/// Marks a midibox as random, so that it reports that it never repeats
+pub struct Aperiodic {
+    midibox: Box<dyn Midibox>,
+}
+
+impl Midibox for Aperiodic {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        self.midibox.next()
+    }
+
+    fn reset(&mut self) {
+        self.midibox.reset()
+    }
+
+    fn seek(&mut self, tick: u64) {
+        self.midibox.seek(tick)
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        self.midibox.step_length()
+    }
+
+    fn period(&self) -> Option<Period> {
+        None
+    }
+}
+
+pub fn aperiodic(midibox: Box<dyn Midibox>) -> Box<dyn Midibox> {
+    Box::new(Aperiodic { midibox })
+}
 
 pub fn random_velocity(midibox: Box<dyn Midibox>) -> Box<dyn Midibox> {
-    map_notes(midibox, |m| {
-        let v = rand::thread_rng().gen_range(0..99);
+    random_velocity_with_rng(midibox, session_rng())
+}
+
+pub fn random_velocity_with_rng(midibox: Box<dyn Midibox>, rng: StdRng) -> Box<dyn Midibox> {
+    let rng = RefCell::new(rng);
+    aperiodic(map_notes(midibox, move |m| {
+        let v = rng.borrow_mut().gen_range(0..99);
         let factor = (v as f64) / (100_f64);
         m.set_velocity((m.velocity as f64 * factor) as u8)
-    })
+    }))
 }
 
 pub fn random_velocity_range(
@@ -18,7 +96,156 @@ pub fn random_velocity_range(
     min_velocity: u8,
     max_velocity: u8
 ) -> Box<dyn Midibox> {
-    map_notes(midibox, move |m|
-        m.set_velocity(rand::thread_rng().gen_range(min_velocity..max_velocity))
-    )
-}
\ No newline at end of file
+    random_velocity_range_with_rng(midibox, min_velocity, max_velocity, session_rng())
+}
+
+pub fn random_velocity_range_with_rng(
+    midibox: Box<dyn Midibox>,
+    min_velocity: u8,
+    max_velocity: u8,
+    rng: StdRng
+) -> Box<dyn Midibox> {
+    let rng = RefCell::new(rng);
+    aperiodic(map_notes(midibox, move |m|
+        m.set_velocity(rng.borrow_mut().gen_range(min_velocity..max_velocity))
+    ))
+}
+
+/// Draws from a normal distribution centered on zero using the Box-Muller transform
+fn gaussian<R: Rng>(rng: &mut R, std_dev: f64) -> f64 {
+    if std_dev <= 0.0 {
+        return 0.0;
+    }
+    let u1: f64 = 1.0 - rng.gen::<f64>();
+    let u2: f64 = rng.gen::<f64>();
+    std_dev * (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+/// Describes how to humanize the notes produced by a Midibox.
+///
+/// Jitter is drawn from normal distributions with the configured standard deviations. Since
+/// notes can't start before their tick, timing jitter only ever delays a note, and duration
+/// jitter never lengthens a step, so humanized channels stay in time with the others.
+#[derive(Debug, Clone)]
+pub struct Humanize {
+    seed: u64,
+    velocity: f64,
+    timing: f64,
+    duration: f64,
+    accents: Vec<f64>,
+}
+
+impl Humanize {
+    pub fn new(seed: u64) -> Self {
+        Humanize { seed, velocity: 0.0, timing: 0.0, duration: 0.0, accents: vec![1.0] }
+    }
+
+    /// Humanizes with a seed drawn from the session, see [session_rng]
+    pub fn from_session() -> Self {
+        Humanize::new(session_rng().next_u64())
+    }
+
+    /// Standard deviation of the velocity jitter, in MIDI velocity units
+    pub fn velocity(mut self, std_dev: f64) -> Self {
+        self.velocity = std_dev;
+        self
+    }
+
+    /// Standard deviation of the timing jitter, in fractions of a tick
+    pub fn timing(mut self, std_dev: f64) -> Self {
+        self.timing = std_dev;
+        self
+    }
+
+    /// Standard deviation of the duration jitter, in ticks
+    pub fn duration(mut self, std_dev: f64) -> Self {
+        self.duration = std_dev;
+        self
+    }
+
+    /// Velocity factors applied to successive steps, repeating every `accents.len()` steps
+    pub fn accents(mut self, accents: Vec<f64>) -> Self {
+        self.accents = if accents.is_empty() { vec![1.0] } else { accents };
+        self
+    }
+
+    pub fn wrap(self, midibox: Box<dyn Midibox>) -> Box<dyn Midibox> {
+        let mut seeder = StdRng::seed_from_u64(self.seed);
+        let note_rng = RefCell::new(StdRng::from_rng(&mut seeder).unwrap());
+        let chord_rng = RefCell::new(StdRng::from_rng(&mut seeder).unwrap());
+        let Humanize { velocity, timing, duration, accents, .. } = self;
+
+        let beats = accents.len();
+        let accented = map_beat(midibox, beats, move |m, beat| {
+            let mut rng = note_rng.borrow_mut();
+            let v = m.velocity as f64 * accents[beat] + gaussian(&mut *rng, velocity);
+            let delay = gaussian(&mut *rng, timing).abs() as f32;
+            m.set_velocity(v.round().clamp(1.0, 127.0) as u8)
+                .set_offset(m.offset + delay)
+        });
+
+        aperiodic(map_chords(accented, move |c| {
+            let step = c.total_duration();
+            let mut rng = chord_rng.borrow_mut();
+            let mut notes: Vec<Midi> = c.notes.iter()
+                .map(|m| if m.duration == 0 {
+                    *m
+                } else {
+                    let d = m.duration as f64 + gaussian(&mut *rng, duration);
+                    m.set_duration(d.round().clamp(1.0, step as f64) as u32)
+                })
+                .collect();
+            // hold the channel for the original step so that shortened notes don't pull the
+            // following notes earlier
+            if notes.iter().map(|m| m.duration).max().unwrap_or(0) < step {
+                notes.push(Midi::rest().set_duration(step));
+            }
+            Chord::new(notes)
+        }))
+    }
+}
+
+pub fn humanize(midibox: Box<dyn Midibox>, humanize: Humanize) -> Box<dyn Midibox> {
+    humanize.wrap(midibox)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midi::Midi;
+    use crate::rand::Humanize;
+    use crate::sequences::Seq;
+    use crate::tone::Tone;
+
+    #[test]
+    fn humanize_is_reproducible() {
+        let seq = Seq::new(vec![Tone::C.oct(4) * 4, Tone::E.oct(4) * 4, Tone::G.oct(4) * 4]);
+        let humanize = Humanize::new(42).velocity(10.0).timing(0.2).duration(1.0)
+            .accents(vec![1.2, 0.8]);
+        let mut first = humanize.clone().wrap(seq.midibox());
+        let mut second = humanize.wrap(seq.midibox());
+        for _ in 0..12 {
+            let notes = first.next().unwrap();
+            assert_eq!(notes, second.next().unwrap());
+            // every step still spans the original duration
+            assert_eq!(notes.iter().map(|m| m.duration).max(), Some(4));
+        }
+    }
+
+    #[test]
+    fn humanize_within_bounds() {
+        let seq = Seq::new(vec![Tone::C.oct(4) * 4]);
+        let mut humanized = Humanize::new(7).velocity(5.0).timing(0.1).duration(0.5)
+            .wrap(seq.midibox());
+        let notes: Vec<Midi> = (0..50)
+            .flat_map(|_| humanized.next().unwrap())
+            .filter(|m| !m.is_rest())
+            .collect();
+        assert!(notes.iter().any(|m| m.velocity != 100));
+        assert!(notes.iter().any(|m| m.offset > 0.0));
+        assert!(notes.iter().any(|m| m.duration < 4));
+        // within five standard deviations, and never early or longer than the step
+        assert!(notes.iter().all(|m| (75..=125).contains(&m.velocity)));
+        assert!(notes.iter().all(|m| (0.0..=0.5).contains(&m.offset)));
+        assert!(notes.iter().all(|m| (1..=4).contains(&m.duration)));
+    }
+}
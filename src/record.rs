@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use midir::MidiInputConnection;
+use crate::Midibox;
+use crate::chord::Chord;
+use crate::input::{listen, MidiMessage};
+use crate::midi::{Midi, MutMidi};
+use crate::sequences::Seq;
+
+
+/// A loop of notes recorded from a MIDI input, on the player's tick grid.
+///
+/// Notes are only recorded while the recording is armed, and are added to the notes already in
+/// the loop, so that a part can be built up over several passes. Times are measured by the
+/// [Recorder] playing the loop, which is polled by the player on every tick.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    loop_length: u64,
+    grid: u64,
+    armed: bool,
+    // recorded notes with their start in ticks into the loop
+    notes: Vec<(u64, Midi)>,
+    // keys held down with the tick they were pressed on
+    held: HashMap<u8, (u64, u8)>,
+    // ticks played since the start
+    time: u64,
+}
+
+impl Recording {
+    pub fn new(loop_length: u64) -> Self {
+        Recording {
+            loop_length: loop_length.max(1),
+            grid: 1,
+            armed: true,
+            notes: vec![],
+            held: HashMap::new(),
+            time: 0,
+        }
+    }
+
+    pub fn bars(bars: u64, ticks_per_bar: u64) -> Self {
+        Recording::new(bars * ticks_per_bar)
+    }
+
+    /// Overdubs onto an existing sequence. The loop is as long as the sequence.
+    pub fn from_seq(seq: &Seq) -> Self {
+        let mut recording = Recording::new(seq.total_duration() as u64);
+        let mut start = 0;
+        for chord in seq.get_chords() {
+            recording.notes.extend(chord.notes.iter()
+                .filter(|n| !n.is_rest() && n.duration > 0)
+                .map(|n| (start, *n)));
+            start += chord.total_duration().max(1) as u64;
+        }
+        recording
+    }
+
+    /// Rounds the start and end of recorded notes to the nearest multiple of `grid` ticks
+    pub fn quantize(mut self, grid: u64) -> Self {
+        self.grid = grid.max(1);
+        self
+    }
+
+    pub fn set_armed(&mut self, armed: bool) {
+        self.armed = armed;
+        if !armed {
+            self.held.clear();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.notes.clear();
+        self.held.clear();
+    }
+
+    pub fn handle(&mut self, message: MidiMessage) {
+        if !self.armed {
+            return;
+        }
+        let now = self.quantized(self.time.saturating_sub(1));
+        match message {
+            MidiMessage::NoteOn { key, velocity, .. } => {
+                self.held.insert(key, (now, velocity));
+            }
+            MidiMessage::NoteOff { key, .. } => {
+                if let Some((start, velocity)) = self.held.remove(&key) {
+                    // octaves below C0 can't be represented
+                    if key < 12 {
+                        return;
+                    }
+                    let duration = (now.saturating_sub(start)).clamp(self.grid, self.loop_length);
+                    self.notes.push((
+                        start % self.loop_length,
+                        Midi::from(key).set_velocity(velocity).set_duration(duration as u32)
+                    ));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The recorded loop as a sequence. A sequence can't hold notes that overlap the next
+    /// step, so notes are cut short where the next note starts.
+    pub fn seq(&self) -> Seq {
+        let mut starts: Vec<u64> = self.notes.iter().map(|(start, _)| *start).collect();
+        starts.sort();
+        starts.dedup();
+
+        let mut chords = vec![];
+        match starts.first() {
+            Some(0) => {}
+            first => {
+                let until = first.copied().unwrap_or(self.loop_length);
+                chords.push(Chord::note(Midi::rest().set_duration(until as u32)));
+            }
+        }
+        for (i, start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(self.loop_length);
+            let step = (end - start) as u32;
+            let mut notes: Vec<Midi> = self.notes.iter()
+                .filter(|(s, _)| s == start)
+                .map(|(_, n)| n.set_duration(n.duration.min(step)))
+                .collect();
+            if notes.iter().all(|n| n.duration < step) {
+                notes.push(Midi::rest().set_duration(step));
+            }
+            chords.push(Chord::new(notes));
+        }
+        Seq::chords(chords)
+    }
+
+    fn quantized(&self, tick: u64) -> u64 {
+        (tick + self.grid / 2) / self.grid * self.grid
+    }
+
+    fn tick(&mut self) -> Vec<Midi> {
+        let position = self.time % self.loop_length;
+        self.time += 1;
+        self.notes.iter().filter(|(start, _)| *start == position).map(|(_, n)| *n).collect()
+    }
+}
+
+/// Plays a recording while it's being recorded, one tick at a time.
+pub struct Recorder {
+    recording: Arc<Mutex<Recording>>,
+    // keeps the input open while the recorder plays
+    _connection: Option<MidiInputConnection<()>>,
+}
+
+impl Recorder {
+    /// Plays a recording fed from elsewhere
+    pub fn wrap(recording: Arc<Mutex<Recording>>) -> Box<dyn Midibox> {
+        Box::new(Recorder { recording, _connection: None })
+    }
+
+    /// Records the notes played on the MIDI input port with the given index. `recording` can
+    /// be used to arm the recording and to get the recorded sequence while playing.
+    pub fn listen(
+        port_id: usize,
+        recording: Arc<Mutex<Recording>>
+    ) -> Result<Box<dyn Midibox>, Box<dyn Error>> {
+        let input_recording = Arc::clone(&recording);
+        let connection = listen(port_id, move |message| {
+            input_recording.lock().unwrap().handle(message)
+        })?;
+        Ok(Box::new(Recorder { recording, _connection: Some(connection) }))
+    }
+}
+
+impl Midibox for Recorder {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        Some(self.recording.lock().unwrap().tick())
+    }
+
+    fn reset(&mut self) {
+        let mut recording = self.recording.lock().unwrap();
+        recording.time = 0;
+        recording.held.clear();
+    }
+
+    fn step_length(&self) -> Option<u32> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::input::MidiMessage;
+    use crate::midi::MutMidi;
+    use crate::record::{Recorder, Recording};
+    use crate::sequences::Seq;
+    use crate::tone::Tone;
+
+    #[test]
+    fn record_and_overdub() {
+        let seq = Seq::new(vec![Tone::C.oct(4) * 4, Tone::Rest.oct(4) * 4]);
+        let recording = Arc::new(Mutex::new(Recording::from_seq(&seq).quantize(2)));
+        let mut recorder = Recorder::wrap(recording.clone());
+        let mut played = vec![];
+        for tick in 0..8 {
+            played.push(recorder.next().unwrap().iter().map(|n| n.tone).collect::<Vec<Tone>>());
+            match tick {
+                3 => recording.lock().unwrap()
+                    .handle(MidiMessage::NoteOn { channel: 0, key: 64, velocity: 90 }),
+                6 => recording.lock().unwrap().handle(MidiMessage::NoteOff { channel: 0, key: 64 }),
+                _ => {}
+            }
+        }
+        assert_eq!(played[0], vec![Tone::C]);
+
+        let recorded = recording.lock().unwrap().seq();
+        let steps: Vec<(Tone, u32)> = recorded.get_chords().iter()
+            .map(|c| (c.notes[0].tone, c.total_duration()))
+            .collect();
+        assert_eq!(steps, vec![(Tone::C, 4), (Tone::E, 4)]);
+        assert_eq!(recorder.next().unwrap()[0].tone, Tone::C);
+    }
+}
@@ -10,6 +10,12 @@ pub struct Scale {
 }
 
 impl Scale {
+    /// Builds a scale from the semitones between its successive tones, ending back at the root
+    /// an octave up
+    pub fn new(root: Tone, intervals: Vec<u8>) -> Self {
+        Scale { root, intervals }
+    }
+
     pub fn major(root: Tone) -> Self {
         Scale {
             root,
@@ -40,6 +46,61 @@ impl Scale {
         midi
     }
 
+    /// The tones of the scale from the root in `low_oct` up to the last tone before the root
+    /// in `high_oct + 1`, leaving out notes above the MIDI range
+    pub fn range(&self, low_oct: u8, high_oct: u8) -> Vec<Midi> {
+        (low_oct..=high_oct)
+            .flat_map(|oct| self.midi(oct))
+            .filter(|n| n.u8_maybe().is_some_and(|v| v <= 127))
+            .collect()
+    }
+
+    /// Whether the note is one of the tones of the scale. Rests are never in the scale.
+    pub fn contains(&self, midi: Midi) -> bool {
+        match (midi.u8_maybe(), self.root.u8(0)) {
+            (Some(v), Some(root)) => self.pitch_classes().contains(&((v + 12 - root % 12) % 12)),
+            _ => false,
+        }
+    }
+
+    /// Moves the note to a tone of the scale, in the given direction. Notes already in the
+    /// scale and rests are left alone, as are notes with no tone of the scale between them and
+    /// the end of the MIDI note range.
+    pub fn quantize(&self, midi: Midi, snap: Snap) -> Midi {
+        let v = match midi.u8_maybe() {
+            Some(v) => v as i16,
+            None => return midi,
+        };
+        for distance in 0..12_i16 {
+            let candidates = match snap {
+                // prefer the lower tone when both are as near
+                Snap::Nearest => vec![v - distance, v + distance],
+                Snap::Up => vec![v + distance],
+                Snap::Down => vec![v - distance],
+            };
+            // octaves below C0 can't be represented
+            for candidate in candidates.into_iter().filter(|c| (12..=127).contains(c)) {
+                let snapped = midi.set_pitch_u8(Some(candidate as u8));
+                if self.contains(snapped) {
+                    return snapped;
+                }
+            }
+        }
+        midi
+    }
+
+    // semitones above the root of each tone of the scale
+    fn pitch_classes(&self) -> Vec<u8> {
+        self.intervals.iter()
+            .take(self.intervals.len().saturating_sub(1))
+            .scan(0, |semitones, interval| {
+                *semitones = (*semitones + interval) % 12;
+                Some(*semitones)
+            })
+            .chain(std::iter::once(0))
+            .collect()
+    }
+
     pub fn make_chord(&self, oct: u8, degree: Degree, pitches: &Vec<Pitch>) -> Option<Chord> {
         let scale_root = Midi::from_tone(self.root, oct);
         match self.harmonize_up(scale_root, degree) {
@@ -104,6 +165,8 @@ impl Scale {
         };
     }
 
+    /// The note the degree below in the scale, or `None` if the note isn't in the scale or the
+    /// harmony would fall below C0
     pub fn harmonize_down(&self, midi: Midi, harmonize: Degree) -> Option<Midi> {
         let tones = self.tones();
         let degree_maybe = tones.into_iter().position(|t| t.eq(&midi.tone));
@@ -123,7 +186,9 @@ impl Scale {
                     .cycle()
                     .take(harmonize.steps())
                     .sum();
-                let new = Midi::from_option(midi.u8_maybe().map(|v| v - steps_to_lower));
+                // notes below C0 can't be represented
+                let lowered = midi.u8_maybe()?.checked_sub(steps_to_lower).filter(|v| *v >= 12)?;
+                let new = Midi::from(lowered);
                 return Some(midi.set_pitch(
                     new.tone,
                     new.oct,
@@ -133,6 +198,14 @@ impl Scale {
     }
 }
 
+/// The direction in which `Scale::quantize` looks for a tone of the scale
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Snap {
+    Nearest,
+    Up,
+    Down,
+}
+
 pub enum Pitch {
     Harmonize(Degree, Direction),
     Transpose(Interval, Direction)
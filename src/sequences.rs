@@ -1,7 +1,13 @@
 use std::ops::{Add, Sub};
+use std::sync::Arc;
+use crossbeam::atomic::AtomicCell;
+use rand::Rng;
+use rand::rngs::StdRng;
 use crate::Midibox;
 use crate::chord::Chord;
 use crate::midi::{Midi, MutMidi};
+use crate::period::{lcm, Period};
+use crate::rand::session_rng;
 use crate::scale::{Degree, Interval, Scale};
 use crate::tone::Tone;
 
@@ -18,51 +24,90 @@ macro_rules! seq {
     };
 }
 
+/// A trig condition deciding whether a step of a sequence plays on a given loop.
+///
+/// A step whose condition fails is played as a rest of the same duration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    /// Always play the step
+    Always,
+    /// Play the step with the given probability, between 0 and 1
+    Probability(f64),
+    /// `Every(a, b)` plays the step on the a-th of every b loops, counting from 1
+    Every(usize, usize),
+    /// Play the step on the first loop only
+    First,
+    /// Play the step on every loop but the first
+    NotFirst,
+    /// Play the step while the sequence's fill is enabled
+    Fill,
+    /// Play the step while the sequence's fill is disabled
+    NotFill,
+    /// Play the step if the most recently evaluated condition passed
+    Pre,
+    /// Play the step if the most recently evaluated condition failed
+    NotPre,
+    /// Play the step if the step before it played. Steps split from a single step, like
+    /// ratchets, follow the first of them so that they all play or rest together.
+    Follow,
+}
+
 // A looping sequence of statically defined notes.
 #[derive(Debug, Clone)]
 pub struct Seq {
     /// The notes that can be produced by a sequence
     notes: Vec<Chord>,
+    /// The trig condition of each note
+    conditions: Vec<Condition>,
+    /// Whether the sequence is playing a fill, see [Condition::Fill]
+    fill: Arc<AtomicCell<bool>>,
     /// The index of the play head into notes
     head_position: usize,
 }
 
 impl Seq {
     pub fn new(notes: Vec<Midi>) -> Self {
-        Seq {
-            notes: notes.into_iter().map(|n| Chord::note(n)).collect(),
-            head_position: 0,
-        }
+        Seq::chords(notes.into_iter().map(|n| Chord::note(n)).collect())
     }
 
     pub fn chords(notes: Vec<Chord>) -> Self {
         Seq {
+            conditions: vec![Condition::Always; notes.len()],
             notes,
+            fill: Arc::new(AtomicCell::new(false)),
             head_position: 0,
         }
     }
 
     pub fn empty() -> Self {
-        Seq {
-            notes: Vec::new(),
-            head_position: 0,
-        }
+        Seq::chords(Vec::new())
     }
 
     pub fn get_chords(&self) -> &Vec<Chord> {
         return &self.notes;
     }
 
+    pub fn get_conditions(&self) -> &Vec<Condition> {
+        &self.conditions
+    }
+
+    pub fn get_fill(&self) -> Arc<AtomicCell<bool>> {
+        self.fill.clone()
+    }
+
     pub fn render(&self) -> IterSeq {
         IterSeq {
-            iter: Box::new(
-                self.notes
-                    .clone()
-                    .into_iter()
-                    .map(|m| m.notes.clone())
-                    .cycle()
-                    .skip(self.head_position)
-            )
+            notes: self.notes.clone(),
+            conditions: self.conditions.clone(),
+            fill: self.fill.clone(),
+            rng: self.conditions.iter()
+                .any(|c| matches!(c, Condition::Probability(_)))
+                .then(session_rng),
+            start: self.head_position,
+            position: self.head_position,
+            loops: 0,
+            previous: false,
+            played: true,
         }
     }
 
@@ -78,8 +123,15 @@ impl Seq {
         self.notes.is_empty()
     }
 
+    /// The number of ticks it takes the player to play through the sequence once. As in the
+    /// player, a step without any sounding notes takes a single tick.
     pub fn total_duration(&self) -> u32 {
-        return self.notes.iter().map(|it| it.total_duration()).sum()
+        return self.notes.iter().map(|it| it.total_duration().max(1)).sum()
+    }
+
+    /// Describes when the rendered sequence repeats, taking trig conditions into account
+    pub fn period(&self) -> Option<Period> {
+        seq_period(&self.notes, &self.conditions)
     }
 
     pub fn fast_forward(mut self, ticks: usize) -> Self {
@@ -113,6 +165,7 @@ impl Seq {
         let mut extend = self.notes;
         extend.append(&mut rhs.notes.clone());
         self.notes = extend;
+        self.conditions.extend(rhs.conditions.iter());
         self
     }
 
@@ -120,15 +173,29 @@ impl Seq {
         let mut new_notes: Vec<Chord> = Vec::with_capacity(
             self.notes.len() * times
         );
+        let mut new_conditions: Vec<Condition> = Vec::with_capacity(
+            self.conditions.len() * times
+        );
         for _ in 0..times {
-            new_notes.extend(self.notes.clone())
+            new_notes.extend(self.notes.clone());
+            new_conditions.extend(self.conditions.iter());
         }
         self.notes = new_notes;
+        self.conditions = new_conditions;
         self
     }
 
     pub fn reverse(mut self) -> Self {
         self.notes = self.notes.into_iter().rev().collect();
+        // steps following another stay behind the step they follow
+        let mut groups: Vec<Vec<Condition>> = Vec::new();
+        for condition in self.conditions.iter() {
+            match (condition, groups.last_mut()) {
+                (Condition::Follow, Some(group)) => group.push(*condition),
+                _ => groups.push(vec![*condition]),
+            }
+        }
+        self.conditions = groups.into_iter().rev().flatten().collect();
         self
     }
 
@@ -157,15 +224,47 @@ impl Seq {
     }
 
     /// Splits each note into a series of metronome ticks adding to the note's duration
-    pub fn split_to_ticks(mut self) -> Self {
-        self.notes = self.notes.into_iter().flat_map(|c| {
-            let old_duration = c.total_duration() as usize;
-            let mut notes: Vec<Chord> = Vec::new();
-            for _ in 0..old_duration {
-                notes.push(c.clone().duration(1))
+    pub fn split_to_ticks(self) -> Self {
+        self.ratchet_steps(|_, c| (c.total_duration(), 1.0))
+    }
+
+    /// Plays a single step as `count` evenly spaced retriggers within its duration, see
+    /// [Chord::ratchet]
+    pub fn ratchet(self, step: usize, count: u32, velocity_ramp: f64) -> Self {
+        self.ratchet_steps(|i, _| if i == step { (count, velocity_ramp) } else { (1, 1.0) })
+    }
+
+    /// Sets the number of retriggers of each step, see [Chord::ratchet]
+    ///
+    /// Like [Seq::mask], the counts are applied starting from the first note of the sequence and
+    /// repeat to match the total number of notes in this sequence.
+    pub fn ratchets(self, counts: &[u32], velocity_ramp: f64) -> Self {
+        if counts.is_empty() {
+            return self;
+        }
+        self.ratchet_steps(|i, _| (counts[i % counts.len()], velocity_ramp))
+    }
+
+    fn ratchet_steps<F>(mut self, ratchet: F) -> Self
+        where F: Fn(usize, &Chord) -> (u32, f64)
+    {
+        let mut notes: Vec<Chord> = Vec::new();
+        let mut conditions: Vec<Condition> = Vec::new();
+        let mut head_position = 0;
+        for (i, (c, condition)) in self.notes.iter().zip(self.conditions.iter()).enumerate() {
+            if i == self.head_position {
+                head_position = notes.len();
+            }
+            let (count, velocity_ramp) = ratchet(i, c);
+            for (r, retrigger) in c.ratchet(count, velocity_ramp).into_iter().enumerate() {
+                notes.push(retrigger);
+                // the step's condition decides for all of its retriggers at once
+                conditions.push(if r == 0 { *condition } else { Condition::Follow });
             }
-            notes
-        }).collect::<Vec<Chord>>();
+        }
+        self.notes = notes;
+        self.conditions = conditions;
+        self.head_position = head_position;
         self
     }
 
@@ -190,6 +289,47 @@ impl Seq {
     pub fn split_notes(self, mask: &Vec<bool>) -> Self {
         self.split_to_ticks().mask(mask)
     }
+
+    /// Sets the trig condition of a single step
+    pub fn condition(mut self, step: usize, condition: Condition) -> Self {
+        if let Some(c) = self.conditions.get_mut(step) {
+            *c = condition;
+        }
+        self
+    }
+
+    /// Sets the trig conditions of the sequence's steps.
+    ///
+    /// Like [Seq::mask], the conditions are applied starting from the first note of the sequence
+    /// and repeat to match the total number of notes in this sequence.
+    pub fn conditions(mut self, conditions: &[Condition]) -> Self {
+        if !conditions.is_empty() {
+            self.conditions = conditions.iter().cycle().take(self.notes.len()).copied().collect();
+        }
+        self
+    }
+
+    /// Shares a switch that enables [Condition::Fill] steps while playing
+    pub fn fill(mut self, fill: Arc<AtomicCell<bool>>) -> Self {
+        self.fill = fill;
+        self
+    }
+}
+
+fn seq_period(notes: &[Chord], conditions: &[Condition]) -> Option<Period> {
+    let mut loops: u64 = 1;
+    for condition in conditions {
+        match condition {
+            Condition::Probability(p) if *p > 0.0 && *p < 1.0 => return None,
+            Condition::First | Condition::NotFirst | Condition::Fill | Condition::NotFill => {
+                return None
+            }
+            Condition::Every(_, b) => loops = lcm(loops, *b as u64),
+            _ => {}
+        }
+    }
+    let durations: Vec<u64> = notes.iter().map(|c| c.total_duration() as u64).collect();
+    Period::of_steps(&durations).map(|p| p.repeat(loops))
 }
 
 impl Add<Seq> for Seq {
@@ -217,11 +357,181 @@ impl Add<Interval> for Seq {
 }
 
 pub struct IterSeq {
-    iter: Box<dyn Iterator<Item=Vec<Midi>>>
+    notes: Vec<Chord>,
+    conditions: Vec<Condition>,
+    fill: Arc<AtomicCell<bool>>,
+    // drawn from the session when rendered, so that the stream depends only on the order that
+    // midiboxes are built in. Sequences without probabilities don't use up a stream.
+    rng: Option<StdRng>,
+    // the index of the first note played
+    start: usize,
+    // the index of the next note to play
+    position: usize,
+    // how many times we've played through the sequence
+    loops: usize,
+    // the result of the last evaluated trig condition
+    previous: bool,
+    // whether the last step played
+    played: bool,
+}
+
+impl IterSeq {
+    fn evaluate(&mut self, condition: Condition) -> bool {
+        let result = match condition {
+            Condition::Always => return true,
+            Condition::Pre => return self.previous,
+            Condition::NotPre => return !self.previous,
+            Condition::Follow => return self.played,
+            // sequences with probabilities are given a generator when rendered
+            Condition::Probability(p) => {
+                self.rng.as_mut().is_some_and(|rng| rng.gen_bool(p.clamp(0.0, 1.0)))
+            }
+            Condition::Every(a, b) => b > 0 && self.loops % b == (a + b - 1) % b,
+            Condition::First => self.loops == 0,
+            Condition::NotFirst => self.loops > 0,
+            Condition::Fill => self.fill.load(),
+            Condition::NotFill => !self.fill.load(),
+        };
+        self.previous = result;
+        result
+    }
 }
 
 impl Midibox for IterSeq {
     fn next(&mut self) -> Option<Vec<Midi>> {
-        self.iter.next()
+        let chord = self.notes.get(self.position)?.clone();
+        let condition = self.conditions.get(self.position).copied().unwrap_or(Condition::Always);
+        let play = self.evaluate(condition);
+        self.played = play;
+        self.position += 1;
+        if self.position >= self.notes.len() {
+            self.position = 0;
+            self.loops += 1;
+        }
+
+        if play {
+            Some(chord.notes)
+        } else {
+            Some(chord.pitch(Tone::Rest, 4).notes)
+        }
+    }
+
+    fn reset(&mut self) {
+        self.position = self.start;
+        self.loops = 0;
+        self.previous = false;
+        self.played = true;
+    }
+
+    fn period(&self) -> Option<Period> {
+        seq_period(&self.notes, &self.conditions)
+    }
+
+    fn seek(&mut self, tick: u64) {
+        self.reset();
+        let step_durations: Vec<u64> = self.notes.iter()
+            .map(|c| c.total_duration().max(1) as u64)
+            .collect();
+        let loop_duration: u64 = step_durations.iter().sum();
+        if loop_duration == 0 {
+            return;
+        }
+        self.loops = (tick / loop_duration) as usize;
+        let mut elapsed = self.loops as u64 * loop_duration;
+        while elapsed < tick {
+            elapsed += step_durations[self.position];
+            self.position += 1;
+            if self.position >= self.notes.len() {
+                self.position = 0;
+                self.loops += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sequences::{Condition, Seq};
+    use crate::tone::Tone;
+
+    fn played(seq: &Seq, steps: usize) -> Vec<bool> {
+        let mut midibox = seq.midibox();
+        (0..steps).map(|_| !midibox.next().unwrap()[0].is_rest()).collect()
+    }
+
+    #[test]
+    fn conditions() {
+        let seq = Seq::new(vec![Tone::C.oct(4), Tone::D.oct(4)])
+            .conditions(&[Condition::Every(2, 3), Condition::Pre]);
+        assert_eq!(
+            played(&seq, 6),
+            vec![false, false, true, true, false, false]
+        );
+
+        let seq = Seq::new(vec![Tone::C.oct(4), Tone::D.oct(4)])
+            .condition(1, Condition::First)
+            .fast_forward(1);
+        assert_eq!(played(&seq, 4), vec![true, true, false, true]);
+    }
+
+    #[test]
+    fn ratchet() {
+        let seq = Seq::new(vec![Tone::C.oct(4) * 7, Tone::D.oct(4) * 2])
+            .ratchet(0, 3, 0.5);
+        let steps: Vec<(u32, u8)> = seq.get_chords().iter()
+            .map(|c| (c.notes[0].duration, c.notes[0].velocity))
+            .collect();
+        assert_eq!(steps, vec![(2, 100), (2, 75), (3, 50), (2, 100)]);
+
+        let split = Seq::new(vec![Tone::C.oct(4) * 3]).split_to_ticks();
+        assert_eq!(split.len(), 3);
+        assert_eq!(split.total_duration(), 3);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn seek() {
+        let seq = Seq::new(vec![Tone::C.oct(4) * 2, Tone::D.oct(4) * 3, Tone::E.oct(4)]);
+        let mut midibox = seq.midibox();
+        midibox.seek(14);
+        assert_eq!(midibox.next().unwrap()[0].tone, Tone::D);
+        midibox.seek(13);
+        assert_eq!(midibox.next().unwrap()[0].tone, Tone::D);
+        midibox.reset();
+        assert_eq!(midibox.next().unwrap()[0].tone, Tone::C);
+    }
+
+    #[test]
+    fn fill() {
+        let seq = Seq::new(vec![Tone::C.oct(4), Tone::D.oct(4)])
+            .conditions(&[Condition::NotFill, Condition::Fill]);
+        let fill = seq.fill.clone();
+        let mut midibox = seq.midibox();
+        assert!(!midibox.next().unwrap()[0].is_rest());
+        assert!(midibox.next().unwrap()[0].is_rest());
+        fill.store(true);
+        assert!(midibox.next().unwrap()[0].is_rest());
+        assert!(!midibox.next().unwrap()[0].is_rest());
+    }
+
+    #[test]
+    fn ratchet_conditions() {
+        let seq = Seq::new(vec![Tone::C.oct(4) * 4, Tone::D.oct(4)])
+            .conditions(&[Condition::Probability(0.5), Condition::Pre])
+            .ratchet(0, 4, 1.0);
+        let mut midibox = seq.midibox();
+        let mut outcomes = vec![];
+        for _ in 0..20 {
+            let step: Vec<bool> = (0..5).map(|_| !midibox.next().unwrap()[0].is_rest()).collect();
+            // all four retriggers play or none do, and the next step sees the step's outcome
+            assert!(step.iter().all(|p| *p == step[0]));
+            outcomes.push(step[0]);
+        }
+        assert!(outcomes.contains(&true) && outcomes.contains(&false));
+
+        let reversed = seq.reverse();
+        assert_eq!(
+            reversed.get_conditions()[..3],
+            [Condition::Pre, Condition::Probability(0.5), Condition::Follow]
+        );
+    }
+}
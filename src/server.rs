@@ -6,6 +6,7 @@ use crossbeam::atomic::AtomicCell;
 use env_logger::init;
 use log::info;
 use tonic::{transport::Server, Request, Response, Status};
+use ::midibox::Midibox;
 use ::midibox::meter::Bpm;
 use ::midibox::player::{PlayerConfig, try_run_ext};
 use ::midibox::scale::{Degree, Interval, Scale};
@@ -27,7 +28,7 @@ pub struct Impl {
     running: Arc<Mutex<HashMap<String, bool>>>,
 }
 
-fn play_default_sequence(name: &str, running: &Arc<Mutex<HashMap<String, bool>>>) {
+fn default_sequence() -> Vec<Box<dyn Midibox>> {
     let scale = Scale::major(Tone::Gb);
 
     let s1 = Seq::new(vec![
@@ -41,16 +42,24 @@ fn play_default_sequence(name: &str, running: &Arc<Mutex<HashMap<String, bool>>>
         Tone::C.oct(2)  * 128,
     ]).transpose_down(Interval::Min2);
 
+    vec![
+        s1.clone(),
+        s1.clone().harmonize_down(&scale, Degree::Fourth),
+        s1.clone().harmonize_up(&scale, Degree::Tenth),
+        s1.clone().harmonize_up(&scale, Degree::Seventh)
+    ].into_iter().map(|seq| seq.midibox()).collect()
+}
+
+fn play(
+    name: &str,
+    mut channels: Vec<Box<dyn Midibox>>,
+    running: &Arc<Mutex<HashMap<String, bool>>>
+) {
     try_run_ext(
         name,
         PlayerConfig::for_port(0),
         &mut Bpm::new(2000),
-        &mut vec![
-            s1.clone(),
-            s1.clone().harmonize_down(&scale, Degree::Fourth),
-            s1.clone().harmonize_up(&scale, Degree::Tenth),
-            s1.clone().harmonize_up(&scale, Degree::Seventh)
-        ].into_iter().map(|seq| seq.midibox()).collect(),
+        &mut channels,
         running
     ).unwrap()
 }
@@ -81,7 +90,8 @@ impl MidiboxPlayer for Impl {
         if !*status.get(&name).unwrap_or(&false) {
             status.insert(name.to_string(), true);
             let running = self.running.clone();
-            thread::spawn(move || play_default_sequence(&name, &running));
+            let channels = default_sequence();
+            thread::spawn(move || play(&name, channels, &running));
         }
 
         let reply = PlayResponse {};
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::error::Error;
+use midir::MidiInputConnection;
+use crate::chord::Chord;
+use crate::input::{listen, MidiMessage};
+use crate::midi::{Midi, MutMidi, NOTE_OFF_MSG, NOTE_ON_MSG};
+use crate::player::{connect_ports, route_note, PlayerConfig, PlayingNote};
+use crate::scale::{Degree, Interval, Scale, Snap};
+
+type Transform = Box<dyn Fn(Chord) -> Chord + Send>;
+
+/// Passes the notes played on a MIDI input through a chain of transforms and out to the ports of
+/// a router, as they're played.
+///
+/// Every key pressed is turned into a chord by the transforms, applied in the order that they
+/// were added, and the chord is released along with the key. Notes that end up outside of the
+/// MIDI note range are dropped. When the chords of several keys share a note, the note starts
+/// with the first of them and sounds until all of them are released.
+pub struct Thru {
+    transforms: Vec<Transform>,
+    // router channel ids by input MIDI channel
+    channels: HashMap<u8, usize>,
+    // the notes of the chord played for each key held down, by input MIDI channel and key
+    held: HashMap<(u8, u8), Vec<PlayingNote>>,
+    // how many of the keys held down sound each note, by router channel id and pitch
+    sounding: HashMap<(usize, u8), usize>,
+}
+
+impl Thru {
+    pub fn new() -> Self {
+        Thru {
+            transforms: vec![],
+            channels: HashMap::new(),
+            held: HashMap::new(),
+            sounding: HashMap::new(),
+        }
+    }
+
+    /// Adds a transform to the end of the chain
+    pub fn map<F>(mut self, f: F) -> Self where F: Fn(Chord) -> Chord + Send + 'static {
+        self.transforms.push(Box::new(f));
+        self
+    }
+
+    pub fn transpose_up(self, interval: Interval) -> Self {
+        self.map(move |chord| chord.transpose_up(&interval))
+    }
+
+    pub fn transpose_down(self, interval: Interval) -> Self {
+        self.map(move |chord| chord.transpose_down(&interval))
+    }
+
+    /// Adds a voice the degree above each note of the scale. Notes outside of the scale aren't
+    /// harmonized.
+    pub fn harmonize_up(self, scale: Scale, degree: Degree) -> Self {
+        self.map(move |chord| with_voices(chord.clone(), chord.harmonize_up(&scale, &degree)))
+    }
+
+    /// Adds a voice the degree below each note of the scale. Notes outside of the scale aren't
+    /// harmonized.
+    pub fn harmonize_down(self, scale: Scale, degree: Degree) -> Self {
+        self.map(move |chord| with_voices(chord.clone(), chord.harmonize_down(&scale, &degree)))
+    }
+
+    /// Snaps every note to a tone of the scale, see [Scale::quantize]
+    pub fn quantize(self, scale: Scale, snap: Snap) -> Self {
+        self.map(move |chord| {
+            Chord::new(chord.notes.into_iter().map(|n| scale.quantize(n, snap)).collect())
+        })
+    }
+
+    /// Maps the velocity of every note, e.g. `|v| 64 + v / 2` to even out the dynamics
+    pub fn velocity_curve<F>(self, curve: F) -> Self where F: Fn(u8) -> u8 + Send + 'static {
+        self.map(move |chord| {
+            Chord::new(chord.notes.into_iter()
+                .map(|n| n.set_velocity(curve(n.velocity).min(127)))
+                .collect())
+        })
+    }
+
+    /// Sends the notes played on an input MIDI channel to a channel of the router. Notes from
+    /// input channels without a mapping go to the channel of the router with the same number.
+    pub fn channel(mut self, input_channel: u8, channel_id: usize) -> Self {
+        self.channels.insert(input_channel, channel_id);
+        self
+    }
+
+    /// The notes to start for a key pressed on an input MIDI channel. Notes already sounding for
+    /// another key keep sounding without being started again.
+    pub fn press(&mut self, channel: u8, key: u8, velocity: u8) -> Vec<PlayingNote> {
+        // octaves below C0 can't be represented
+        if !(12..=127).contains(&key) || self.held.contains_key(&(channel, key)) {
+            return vec![];
+        }
+        let chord = self.transforms.iter()
+            .fold(Chord::note(Midi::from(key).set_velocity(velocity)), |chord, f| f(chord));
+        let channel_id = self.channels.get(&channel).copied().unwrap_or(channel as usize);
+        let mut notes: Vec<PlayingNote> = vec![];
+        for note in chord.notes {
+            let pitch = note.u8_maybe();
+            let playable = pitch.is_some_and(|v| (12..=127).contains(&v));
+            if playable && !notes.iter().any(|p| p.note.u8_maybe() == pitch) {
+                notes.push(PlayingNote { channel_id, start_tick_id: 0, note });
+            }
+        }
+        self.held.insert((channel, key), notes.clone());
+        notes.retain(|p| {
+            let count = self.sounding.entry((channel_id, p.note.u8_maybe().unwrap())).or_insert(0);
+            *count += 1;
+            *count == 1
+        });
+        notes
+    }
+
+    /// The notes to stop for a key released on an input MIDI channel, leaving out the notes that
+    /// other keys held down still sound
+    pub fn release(&mut self, channel: u8, key: u8) -> Vec<PlayingNote> {
+        let mut notes = self.held.remove(&(channel, key)).unwrap_or_default();
+        notes.retain(|p| {
+            let voice = (p.channel_id, p.note.u8_maybe().unwrap());
+            let count = self.sounding.get_mut(&voice).map(|count| {
+                *count -= 1;
+                *count
+            });
+            if count == Some(0) {
+                self.sounding.remove(&voice);
+            }
+            count == Some(0)
+        });
+        notes
+    }
+
+    /// Passes the notes played on the MIDI input port with the given index through to the output
+    /// ports of the player config. Notes are passed through for as long as the returned
+    /// connection is kept alive.
+    pub fn listen(
+        mut self,
+        port_id: usize,
+        player_config: PlayerConfig
+    ) -> Result<MidiInputConnection<()>, Box<dyn Error>> {
+        let mut port_id_to_conn = connect_ports(&player_config)?;
+        listen(port_id, move |message| {
+            let (notes, midi_status) = match message {
+                MidiMessage::NoteOn { channel, key, velocity } => {
+                    (self.press(channel, key, velocity), NOTE_ON_MSG)
+                }
+                MidiMessage::NoteOff { channel, key } => (self.release(channel, key), NOTE_OFF_MSG),
+                _ => return,
+            };
+            for note in notes.iter() {
+                route_note(&player_config, &mut port_id_to_conn, note, midi_status)
+            }
+        })
+    }
+}
+
+impl Default for Thru {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Adds the harmonized notes of a chord to it, leaving out the notes that couldn't be harmonized
+fn with_voices(chord: Chord, harmonized: Chord) -> Chord {
+    Chord::new(chord.notes.into_iter()
+        .chain(harmonized.notes.into_iter().filter(|n| !n.is_rest()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::player::PlayingNote;
+    use crate::scale::{Degree, Interval, Scale, Snap};
+    use crate::thru::Thru;
+    use crate::tone::Tone;
+
+    #[test]
+    fn transform_chain() {
+        let mut thru = Thru::new()
+            .quantize(Scale::major(Tone::C), Snap::Up)
+            .harmonize_down(Scale::major(Tone::C), Degree::Third)
+            .transpose_up(Interval::Oct)
+            .velocity_curve(|v| v / 2)
+            .channel(0, 2);
+        let pressed = thru.press(0, 61, 100);
+        let notes: Vec<(usize, Tone, u8, u8)> = pressed.iter()
+            .map(|p| (p.channel_id, p.note.tone, p.note.oct, p.note.velocity))
+            .collect();
+        assert_eq!(notes, vec![(2, Tone::D, 5, 50), (2, Tone::B, 4, 50)]);
+        assert!(thru.press(0, 61, 100).is_empty());
+
+        let released = thru.release(0, 61);
+        assert_eq!(released.len(), 2);
+        assert!(thru.release(0, 61).is_empty());
+        assert_eq!(thru.press(3, 60, 100)[0].channel_id, 3);
+    }
+
+    #[test]
+    fn shared_notes() {
+        let mut thru = Thru::new().harmonize_up(Scale::major(Tone::C), Degree::Fifth);
+        let pitches = |notes: Vec<PlayingNote>| {
+            notes.iter().map(|p| p.note.u8_maybe().unwrap()).collect::<Vec<u8>>()
+        };
+        assert_eq!(pitches(thru.press(0, 60, 100)), vec![60, 67]);
+        // G is already sounding for C
+        assert_eq!(pitches(thru.press(0, 67, 100)), vec![74]);
+        assert_eq!(pitches(thru.release(0, 60)), vec![60]);
+        assert_eq!(pitches(thru.release(0, 67)), vec![67, 74]);
+
+        // the lowest notes can't be harmonized down
+        let mut thru = Thru::new().harmonize_down(Scale::major(Tone::C), Degree::Third);
+        assert_eq!(pitches(thru.press(0, 12, 100)), vec![12]);
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::Arc;
+use crossbeam::atomic::AtomicCell;
+use rand::Rng;
+use rand::rngs::StdRng;
+use crate::Midibox;
+use crate::midi::Midi;
+use crate::rand::session_rng;
+use crate::scale::Scale;
+
+pub const MAX_LENGTH: usize = 16;
+
+/// A sequencer modeled on the Music Thing Turing Machine.
+///
+/// A 16-bit shift register shifts along on every step, and the bit `length` steps back is fed
+/// around to the start, flipped with the given probability. At 0 the register is locked into a loop of `length` steps, at 1 into
+/// a loop of twice as many steps with the bits inverted the second time around, and in between
+/// the loop slowly mutates. Both knobs can be turned while playing.
+///
+/// The lowest 8 bits of the register pick a tone of the scale within the range of octaves, and
+/// with gates enabled a note only plays when the lowest bit is set. Bits keep shifting along
+/// past the loop, so loops shorter than 8 steps still pick from across the whole range.
+pub struct TuringMachine {
+    scale: Scale,
+    probability: Arc<AtomicCell<f64>>,
+    length: Arc<AtomicCell<usize>>,
+    low_oct: u8,
+    high_oct: u8,
+    note_duration: u32,
+    gates: bool,
+}
+
+impl TuringMachine {
+    pub fn new(
+        scale: Scale,
+        probability: Arc<AtomicCell<f64>>,
+        length: Arc<AtomicCell<usize>>
+    ) -> Self {
+        TuringMachine {
+            scale,
+            probability,
+            length,
+            low_oct: 3,
+            high_oct: 4,
+            note_duration: 1,
+            gates: false,
+        }
+    }
+
+    /// The lowest and highest octaves notes are picked from
+    pub fn range(mut self, low_oct: u8, high_oct: u8) -> Self {
+        self.low_oct = low_oct.min(high_oct);
+        self.high_oct = high_oct.max(low_oct);
+        self
+    }
+
+    pub fn duration(mut self, note_duration: u32) -> Self {
+        self.note_duration = note_duration.max(1);
+        self
+    }
+
+    /// Rests on the steps where the lowest bit of the register is not set
+    pub fn gates(mut self, gates: bool) -> Self {
+        self.gates = gates;
+        self
+    }
+
+    pub fn generate(self) -> Box<dyn Midibox> {
+        self.generate_with_rng(session_rng())
+    }
+
+    pub fn generate_with_rng(self, mut rng: StdRng) -> Box<dyn Midibox> {
+        let register = rng.gen::<u16>();
+        Box::new(ShiftRegister {
+            notes: self.scale.range(self.low_oct, self.high_oct),
+            machine: self,
+            register,
+            start: register,
+            rng,
+        })
+    }
+}
+
+pub struct ShiftRegister {
+    machine: TuringMachine,
+    // the tones of the scale within range, from lowest to highest
+    notes: Vec<Midi>,
+    register: u16,
+    // the register's contents when the machine started
+    start: u16,
+    rng: StdRng,
+}
+
+impl ShiftRegister {
+    fn shift(&mut self) {
+        let length = self.machine.length.load().clamp(1, MAX_LENGTH);
+        let probability = self.machine.probability.load().clamp(0.0, 1.0);
+        let mut bit = (self.register >> (length - 1)) & 1;
+        if self.rng.gen_bool(probability) {
+            bit ^= 1;
+        }
+        self.register = (self.register << 1) | bit;
+    }
+}
+
+impl Midibox for ShiftRegister {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        if self.notes.is_empty() {
+            return None;
+        }
+        self.shift();
+        let duration = self.machine.note_duration;
+        if self.machine.gates && self.register & 1 == 0 {
+            return Some(vec![Midi::rest().set_duration(duration)]);
+        }
+        let value = (self.register & 0xFF) as usize;
+        let note = self.notes[value * self.notes.len() / 256];
+        Some(vec![note.set_duration(duration)])
+    }
+
+    fn reset(&mut self) {
+        self.register = self.start;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crossbeam::atomic::AtomicCell;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use crate::Midibox;
+    use crate::scale::Scale;
+    use crate::tone::Tone;
+    use crate::turing::TuringMachine;
+
+    fn pitches(midibox: &mut Box<dyn Midibox>, steps: usize) -> Vec<u8> {
+        (0..steps).map(|_| midibox.next().unwrap()[0].u8_maybe().unwrap()).collect()
+    }
+
+    #[test]
+    fn locked_loops() {
+        let probability = Arc::new(AtomicCell::new(0.0));
+        let length = Arc::new(AtomicCell::new(8));
+        let mut machine = TuringMachine::new(Scale::major(Tone::C), probability.clone(), length)
+            .generate_with_rng(StdRng::seed_from_u64(11));
+        let tones = pitches(&mut machine, 16);
+        assert_eq!(tones[..8], tones[8..]);
+
+        // fully flipping inverts the loop every time around, so it takes twice as long to repeat
+        probability.store(1.0);
+        let tones = pitches(&mut machine, 32);
+        assert_eq!(tones[..16], tones[16..]);
+        assert_ne!(tones[..8], tones[8..16]);
+    }
+
+    #[test]
+    fn short_loops() {
+        let probability = Arc::new(AtomicCell::new(0.0));
+        let length = Arc::new(AtomicCell::new(4));
+        let mut machine = TuringMachine::new(Scale::major(Tone::C), probability, length)
+            .generate_with_rng(StdRng::seed_from_u64(5));
+        // once the bits from before the loop have shifted out of the lowest 8
+        pitches(&mut machine, 8);
+        let tones = pitches(&mut machine, 8);
+        assert_eq!(tones[..4], tones[4..]);
+        let mut distinct = tones.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert!(distinct.len() > 1, "{:?}", tones);
+    }
+}
@@ -0,0 +1,213 @@
+use rand::Rng;
+use rand::rngs::StdRng;
+use crate::Midibox;
+use crate::chord::Chord;
+use crate::midi::{Midi, MutMidi};
+use crate::rand::session_rng;
+use crate::scale::Scale;
+use crate::sequences::Seq;
+
+
+/// Describes a melody that wanders up and down the degrees of a scale.
+///
+/// Each step moves by a number of scale degrees drawn from weighted step sizes, without leaving
+/// the range of octaves. When chords are supplied, moves that land on a tone of the chord
+/// sounding at the time are made more likely by the attraction factor.
+#[derive(Debug, Clone)]
+pub struct RandomWalk {
+    scale: Scale,
+    low_oct: u8,
+    high_oct: u8,
+    steps: Vec<(i32, f64)>,
+    rest_probability: f64,
+    chords: Option<Seq>,
+    attraction: f64,
+    note_duration: u32,
+}
+
+impl RandomWalk {
+    pub fn new(scale: Scale) -> Self {
+        RandomWalk {
+            scale,
+            low_oct: 3,
+            high_oct: 5,
+            steps: vec![(-2, 1.0), (-1, 3.0), (0, 1.0), (1, 3.0), (2, 1.0)],
+            rest_probability: 0.0,
+            chords: None,
+            attraction: 1.0,
+            note_duration: 1,
+        }
+    }
+
+    /// The lowest and highest octaves the walk may visit, each starting on the root of the scale
+    pub fn range(mut self, low_oct: u8, high_oct: u8) -> Self {
+        self.low_oct = low_oct.min(high_oct);
+        self.high_oct = high_oct.max(low_oct);
+        self
+    }
+
+    /// The moves the walk can make, in scale degrees, with their relative weights
+    pub fn steps(mut self, steps: Vec<(i32, f64)>) -> Self {
+        self.steps = steps;
+        self
+    }
+
+    /// The probability of resting instead of playing a note on each step
+    pub fn rest_probability(mut self, p: f64) -> Self {
+        self.rest_probability = p.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Pulls the walk towards the tones of the chords, played in a loop. Moves landing on a
+    /// chord tone are `attraction` times as likely.
+    pub fn chords(mut self, chords: Seq, attraction: f64) -> Self {
+        self.chords = Some(chords);
+        self.attraction = attraction.max(0.0);
+        self
+    }
+
+    pub fn duration(mut self, note_duration: u32) -> Self {
+        self.note_duration = note_duration.max(1);
+        self
+    }
+
+    pub fn generate(&self) -> Box<dyn Midibox> {
+        self.generate_with_rng(session_rng())
+    }
+
+    pub fn generate_with_rng(&self, rng: StdRng) -> Box<dyn Midibox> {
+        let notes = self.scale.range(self.low_oct, self.high_oct);
+        Box::new(Walk {
+            position: notes.len() / 2,
+            notes,
+            walk: self.clone(),
+            rng,
+            time: 0,
+        })
+    }
+
+    /// The chord sounding `time` ticks in
+    fn chord_at(&self, time: u64) -> Option<&Chord> {
+        let chords = self.chords.as_ref()?;
+        let length = chords.total_duration() as u64;
+        if length == 0 {
+            return None;
+        }
+        let mut remaining = time % length;
+        for chord in chords.get_chords() {
+            let duration = chord.total_duration().max(1) as u64;
+            if remaining < duration {
+                return Some(chord);
+            }
+            remaining -= duration;
+        }
+        None
+    }
+}
+
+pub struct Walk {
+    walk: RandomWalk,
+    // the tones of the scale within range, from lowest to highest
+    notes: Vec<Midi>,
+    // the index of the last note played
+    position: usize,
+    rng: StdRng,
+    // ticks played since the start
+    time: u64,
+}
+
+impl Walk {
+    fn choose_move(&mut self) -> usize {
+        let chord_tones: Vec<u8> = self.walk.chord_at(self.time)
+            .map(|c| c.notes.iter().filter_map(|n| n.u8_maybe()).map(|v| v % 12).collect())
+            .unwrap_or_default();
+        let candidates: Vec<(usize, f64)> = self.walk.steps.iter()
+            .filter_map(|(step, weight)| {
+                let target = self.position as i64 + *step as i64;
+                if target < 0 || target >= self.notes.len() as i64 {
+                    return None;
+                }
+                let on_chord = self.notes[target as usize].u8_maybe()
+                    .is_some_and(|v| chord_tones.contains(&(v % 12)));
+                let weight = if on_chord { weight * self.walk.attraction } else { *weight };
+                Some((target as usize, weight))
+            })
+            .filter(|(_, weight)| *weight > 0.0)
+            .collect();
+
+        let total: f64 = candidates.iter().map(|(_, w)| w).sum();
+        if candidates.is_empty() || total <= 0.0 {
+            return self.position;
+        }
+        let mut target = self.rng.gen::<f64>() * total;
+        for (position, weight) in candidates.iter() {
+            if target < *weight {
+                return *position;
+            }
+            target -= weight;
+        }
+        candidates.last().map(|(p, _)| *p).unwrap_or(self.position)
+    }
+}
+
+impl Midibox for Walk {
+    fn next(&mut self) -> Option<Vec<Midi>> {
+        if self.notes.is_empty() {
+            return None;
+        }
+        let duration = self.walk.note_duration;
+        let rest = self.rng.gen_bool(self.walk.rest_probability);
+        let note = if rest {
+            Midi::rest().set_duration(duration)
+        } else {
+            self.position = self.choose_move();
+            self.notes[self.position].set_duration(duration)
+        };
+        self.time += duration as u64;
+        Some(vec![note])
+    }
+
+    fn reset(&mut self) {
+        self.position = self.notes.len() / 2;
+        self.time = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use crate::chord::Chord;
+    use crate::scale::Scale;
+    use crate::sequences::Seq;
+    use crate::tone::Tone;
+    use crate::walk::RandomWalk;
+
+    #[test]
+    fn stays_in_range_and_scale() {
+        let scale = Scale::major(Tone::D);
+        let mut walk = RandomWalk::new(scale.clone())
+            .range(3, 3)
+            .steps(vec![(-3, 1.0), (3, 1.0)])
+            .generate_with_rng(StdRng::seed_from_u64(3));
+        for _ in 0..100 {
+            let note = walk.next().unwrap()[0];
+            assert!(scale.contains(note));
+            // the octave starting on D3
+            assert!((50..62).contains(&note.u8_maybe().unwrap()));
+        }
+    }
+
+    #[test]
+    fn attraction() {
+        let triad = Chord::new(vec![Tone::C.oct(4), Tone::E.oct(4), Tone::G.oct(4)]);
+        let mut walk = RandomWalk::new(Scale::major(Tone::C))
+            .chords(Seq::chords(vec![triad]).duration(4), 1000.0)
+            .generate_with_rng(StdRng::seed_from_u64(5));
+        let on_chord = (0..100)
+            .map(|_| walk.next().unwrap()[0].tone)
+            .filter(|t| [Tone::C, Tone::E, Tone::G].contains(t))
+            .count();
+        assert!(on_chord > 90);
+    }
+}